@@ -0,0 +1,447 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, MAIN_DB, params};
+use serde_json::{Value, json};
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    config::KatexAssets,
+    data::{Card, CardKind, Output},
+    generator::cloze_numbers,
+};
+
+const SCHEMA: &str = r"
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null,
+    time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+const DEFAULT_DECK_ID: i64 = 1;
+const CARD_KINDS: [CardKind; 4] = [
+    CardKind::Basic,
+    CardKind::BasicAndReversed,
+    CardKind::OptionalReversed,
+    CardKind::Cloze,
+];
+
+/// Same names as the note types created by the add-on
+const fn model_name(kind: CardKind) -> &'static str {
+    match kind {
+        CardKind::Basic => "Ankill",
+        CardKind::BasicAndReversed => "Ankill (reversed)",
+        CardKind::OptionalReversed => "Ankill (optional reversed)",
+        CardKind::Cloze => "Ankill Cloze",
+    }
+}
+
+/// Anki ids are 53 bits at most, so they survive a trip through JSON.
+fn stable_id(kind: &str, name: &str) -> i64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(kind.as_bytes());
+    hasher.update(name.as_bytes());
+    let bytes = hasher.finalize();
+    let mut id = [0; 8];
+    id.copy_from_slice(&bytes.as_bytes()[..8]);
+    i64::from_le_bytes(id) & ((1 << 53) - 1)
+}
+
+fn strip_html(input: &str) -> String {
+    let mut output = String::new();
+    let mut in_tag = false;
+    for c in input.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => output.push(c),
+            _ => {}
+        }
+    }
+
+    output
+}
+
+fn checksum(field: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(field).digest().to_string();
+    i64::from_str_radix(&digest[..8], 16).unwrap_or_default()
+}
+
+pub struct Package<'a> {
+    decks: &'a Output,
+    timestamp: i64,
+    katex: KatexAssets,
+}
+
+impl<'a> Package<'a> {
+    pub fn new(decks: &'a Output) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|f| f.as_secs().try_into().unwrap_or_default())
+            .unwrap_or_default();
+
+        Self {
+            decks,
+            timestamp,
+            katex: KatexAssets::None,
+        }
+    }
+
+    /// Load `katex` in the templates, as the add-on does for the math of the config
+    pub const fn with_katex(mut self, katex: KatexAssets) -> Self {
+        self.katex = katex;
+        self
+    }
+
+    fn template(&self, name: &str, ord: usize, qfmt: &str, afmt: &str) -> Value {
+        json!({
+            "name": name,
+            "ord": ord,
+            "qfmt": format!("{qfmt}{}", self.katex.html()),
+            "afmt": afmt,
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+        })
+    }
+
+    fn model(&self, kind: CardKind) -> Value {
+        let name = model_name(kind);
+        let front = self.template(
+            "Carte",
+            0,
+            "{{Recto}}",
+            "{{FrontSide}}\n\n<hr id=answer>\n\n{{Verso}}",
+        );
+        let (field_names, templates, req, model_type) = match kind {
+            CardKind::Basic => (
+                vec!["Recto", "Verso", "Hash"],
+                vec![front],
+                json!([[0, "any", [0]]]),
+                0,
+            ),
+            CardKind::BasicAndReversed => (
+                vec!["Recto", "Verso", "Hash"],
+                vec![
+                    front,
+                    self.template(
+                        "Carte inverse",
+                        1,
+                        "{{Verso}}",
+                        "{{FrontSide}}\n\n<hr id=answer>\n\n{{Recto}}",
+                    ),
+                ],
+                json!([[0, "any", [0]], [1, "any", [1]]]),
+                0,
+            ),
+            CardKind::OptionalReversed => (
+                vec!["Recto", "Verso", "Hash", "Inverser"],
+                vec![
+                    front,
+                    self.template(
+                        "Carte inverse",
+                        1,
+                        "{{#Inverser}}{{Verso}}{{/Inverser}}",
+                        "{{FrontSide}}\n\n<hr id=answer>\n\n{{Recto}}",
+                    ),
+                ],
+                json!([[0, "any", [0]], [1, "all", [1, 3]]]),
+                0,
+            ),
+            CardKind::Cloze => (
+                vec!["Texte", "Extra", "Hash"],
+                vec![self.template(
+                    "Texte à trous",
+                    0,
+                    "{{cloze:Texte}}",
+                    "{{cloze:Texte}}<br>\n{{Extra}}",
+                )],
+                json!([[0, "any", [0]]]),
+                1,
+            ),
+        };
+
+        let fields = field_names
+            .iter()
+            .enumerate()
+            .map(|(ord, name)| {
+                json!({
+                    "name": name,
+                    "ord": ord,
+                    "font": "Arial",
+                    "media": [],
+                    "rtl": false,
+                    "size": 20,
+                    "sticky": false,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "id": stable_id("model", name),
+            "name": name,
+            "type": model_type,
+            "mod": self.timestamp,
+            "usn": -1,
+            "sortf": 0,
+            "did": DEFAULT_DECK_ID,
+            "tags": [],
+            "vers": [],
+            "flds": fields,
+            "tmpls": templates,
+            "css": ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+            "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+            "latexPost": "\\end{document}",
+            "latexsvg": false,
+            "req": req,
+        })
+    }
+
+    fn deck(&self, id: i64, name: &str) -> Value {
+        json!({
+            "id": id,
+            "name": name,
+            "mod": self.timestamp,
+            "usn": -1,
+            "conf": 1,
+            "desc": "",
+            "dyn": 0,
+            "collapsed": false,
+            "extendNew": 10,
+            "extendRev": 50,
+            "newToday": [0, 0],
+            "revToday": [0, 0],
+            "lrnToday": [0, 0],
+            "timeToday": [0, 0],
+        })
+    }
+
+    fn deck_config(&self) -> Value {
+        json!({
+            "1": {
+                "id": 1,
+                "name": "Default",
+                "mod": 0,
+                "usn": 0,
+                "maxTaken": 60,
+                "autoplay": true,
+                "timer": 0,
+                "replayq": true,
+                "dyn": false,
+                "new": {
+                    "bury": true,
+                    "delays": [1, 10],
+                    "initialFactor": 2500,
+                    "ints": [1, 4, 7],
+                    "order": 1,
+                    "perDay": 20,
+                    "separate": true,
+                },
+                "rev": {
+                    "bury": true,
+                    "ease4": 1.3,
+                    "fuzz": 0.05,
+                    "ivlFct": 1,
+                    "maxIvl": 36500,
+                    "minSpace": 1,
+                    "perDay": 100,
+                },
+                "lapse": {
+                    "delays": [10],
+                    "leechAction": 0,
+                    "leechFails": 8,
+                    "minInt": 1,
+                    "mult": 0,
+                },
+            }
+        })
+    }
+
+    /// `next_position` is the new-card position Anki gives the next note added
+    fn collection_config(&self, next_position: i64) -> Value {
+        json!({
+            "activeDecks": [DEFAULT_DECK_ID],
+            "curDeck": DEFAULT_DECK_ID,
+            "newSpread": 0,
+            "collapseTime": 1200,
+            "timeLim": 0,
+            "estTimes": true,
+            "dueCounts": true,
+            "curModel": null,
+            "nextPos": next_position,
+            "sortType": "noteFld",
+            "sortBackwards": false,
+            "addToCur": true,
+        })
+    }
+
+    /// Templates of the note type generating a card for `card`
+    fn card_ords(card: &Card) -> Vec<i64> {
+        match card.kind {
+            CardKind::Basic => vec![0],
+            CardKind::BasicAndReversed | CardKind::OptionalReversed => vec![0, 1],
+            CardKind::Cloze => cloze_numbers(&card.front)
+                .into_iter()
+                .map(|number| i64::try_from(number).unwrap_or(1) - 1)
+                .collect(),
+        }
+    }
+
+    fn insert_card(
+        &self,
+        conn: &Connection,
+        next_id: &mut i64,
+        deck_id: i64,
+        position: i64,
+        card: &Card,
+    ) -> anyhow::Result<()> {
+        let note_id = *next_id;
+        *next_id += 1;
+        let mut fields = vec![card.front.as_str(), card.back.as_str(), card.hash.as_str()];
+        if card.kind == CardKind::OptionalReversed {
+            fields.push("y");
+        }
+        let fields = fields.join("\x1f");
+        let tags = if card.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" {} ", card.tags.join(" "))
+        };
+        let sort_field = strip_html(&card.front);
+
+        conn.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+            params![
+                note_id,
+                card.guid,
+                stable_id("model", model_name(card.kind)),
+                self.timestamp,
+                tags,
+                fields,
+                sort_field,
+                checksum(&sort_field),
+            ],
+        )?;
+
+        for ord in Self::card_ords(card) {
+            conn.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, ?4, ?5, -1, 0, 0, ?6, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                params![*next_id, note_id, deck_id, ord, self.timestamp, position],
+            )?;
+            *next_id += 1;
+        }
+
+        Ok(())
+    }
+
+    fn collection(&self) -> anyhow::Result<Vec<u8>> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(SCHEMA)?;
+
+        let mut decks = BTreeMap::new();
+        decks.insert(
+            DEFAULT_DECK_ID.to_string(),
+            self.deck(DEFAULT_DECK_ID, "Default"),
+        );
+
+        let mut models = BTreeMap::new();
+        for kind in CARD_KINDS {
+            let model = self.model(kind);
+            models.insert(model["id"].to_string(), model);
+        }
+
+        let mut next_id = self.timestamp * 1000;
+        // New cards are due in the order of their notes, like the ones added in Anki
+        let mut position = 0;
+        for (name, deck) in self.decks {
+            let deck_id = stable_id("deck", name);
+            decks.insert(deck_id.to_string(), self.deck(deck_id, name));
+
+            for card in &deck.added {
+                position += 1;
+                self.insert_card(&conn, &mut next_id, deck_id, position, card)?;
+            }
+        }
+
+        conn.execute(
+            "INSERT INTO col VALUES (1, ?1, ?2, ?3, 11, 0, 0, 0, ?4, ?5, ?6, ?7, '{}')",
+            params![
+                self.timestamp,
+                self.timestamp * 1000,
+                self.timestamp * 1000,
+                self.collection_config(position + 1).to_string(),
+                serde_json::to_string(&models)?,
+                serde_json::to_string(&decks)?,
+                self.deck_config().to_string(),
+            ],
+        )?;
+
+        Ok(conn.serialize(MAIN_DB)?.to_vec())
+    }
+
+    pub fn write<W: Write + std::io::Seek>(&self, writer: W) -> anyhow::Result<()> {
+        let mut zip = ZipWriter::new(writer);
+        let options = SimpleFileOptions::default();
+
+        zip.start_file("collection.anki2", options)?;
+        zip.write_all(&self.collection()?)?;
+
+        // Files are stored under their index, `media` maps it to the name used by the cards
+        let media = self
+            .decks
+            .values()
+            .flat_map(|deck| &deck.media)
+            .collect::<BTreeMap<_, _>>();
+        let mut names = BTreeMap::new();
+        for (index, (name, data)) in media.into_iter().enumerate() {
+            zip.start_file(index.to_string(), options)?;
+            zip.write_all(data)?;
+            names.insert(index.to_string(), name);
+        }
+
+        zip.start_file("media", options)?;
+        zip.write_all(serde_json::to_string(&names)?.as_bytes())?;
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    pub fn write_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)?;
+        self.write(file)
+    }
+}
@@ -1,4 +1,6 @@
-use std::{collections::HashMap, io::Read};
+use std::{collections::HashMap, io::Read, path::PathBuf};
+
+use crate::{data::CardKind, git::Pin, highlight};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct Config {
@@ -16,6 +18,183 @@ impl Config {
     }
 }
 
+/// Where the math of the cards is rendered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MathMode {
+    /// Left as `$...$` for KaTeX, loaded by the card template
+    #[default]
+    Client,
+    /// MathML, displayed by the webviews of Anki and AnkiDroid without any script
+    Mathml,
+    /// KaTeX HTML, styled by the KaTeX stylesheet, with MathML for accessibility
+    Html,
+    /// `\(...\)` and `\[...\]`, rendered by the MathJax bundled with Anki
+    Mathjax,
+}
+
+impl MathMode {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Client => "client",
+            Self::Mathml => "mathml",
+            Self::Html => "html",
+            Self::Mathjax => "mathjax",
+        }
+    }
+
+    /// What the card templates load of KaTeX for the math of this mode, Anki renders the others
+    pub const fn katex_assets(self) -> KatexAssets {
+        match self {
+            Self::Client => KatexAssets::Scripts,
+            Self::Html => KatexAssets::Stylesheet,
+            Self::Mathml | Self::Mathjax => KatexAssets::None,
+        }
+    }
+}
+
+/// GitHub flavoured markdown extensions, all on by default as on GitHub and Gitea
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Extensions {
+    pub table: bool,
+    pub strikethrough: bool,
+    pub tasklist: bool,
+    pub footnotes: bool,
+    /// Bare urls and emails become links
+    pub autolink: bool,
+    /// `> [!NOTE]` and the other callouts
+    pub alerts: bool,
+}
+
+impl Extensions {
+    /// Names of the extensions turned off
+    pub fn disabled(&self) -> Vec<&'static str> {
+        [
+            ("table", self.table),
+            ("strikethrough", self.strikethrough),
+            ("tasklist", self.tasklist),
+            ("footnotes", self.footnotes),
+            ("autolink", self.autolink),
+            ("alerts", self.alerts),
+        ]
+        .into_iter()
+        .filter(|(_, enabled)| !enabled)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Self {
+            table: true,
+            strikethrough: true,
+            tasklist: true,
+            footnotes: true,
+            autolink: true,
+            alerts: true,
+        }
+    }
+}
+
+/// Commands drawing the fenced diagrams, reading the source on stdin and writing SVG on stdout
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Diagrams {
+    /// For example `["mmdc", "-i", "-", "-o", "-", "-e", "svg"]`, mermaid is not drawn without it
+    pub mermaid: Vec<String>,
+    /// Graphviz is laid out in process when empty
+    pub dot: Vec<String>,
+    /// Seconds a command has to draw a diagram before it is killed
+    pub timeout: u64,
+}
+
+impl Default for Diagrams {
+    fn default() -> Self {
+        Self {
+            mermaid: Vec::new(),
+            dot: Vec::new(),
+            timeout: 30,
+        }
+    }
+}
+
+/// What runs the git operations of a repository
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GitBackend {
+    /// libgit2, linked in gencore
+    #[default]
+    Builtin,
+    /// The `git` program, with the configuration of the system
+    System,
+}
+
+/// Credentials of a private repository, a token or a helper answers HTTPS and a key SSH
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Auth {
+    /// Private key used over SSH, instead of the agent and the default keys of `~/.ssh`
+    pub ssh_key: Option<PathBuf>,
+    /// Environment variable holding an access token
+    pub token_env: Option<String>,
+    /// User sent with the token, GitLab expects `oauth2`
+    pub username: Option<String>,
+    /// Helper asked for the credentials, as in the `credential.helper` option of git
+    pub credential_helper: Option<String>,
+}
+
+/// How the markdown of a repository is turned into cards
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Level of the headings starting a card
+    pub heading_level: u8,
+    /// Kind of the cards, unless set by the file front matter or the heading
+    pub kind: CardKind,
+    /// Tag every card with the path of its deck
+    pub folder_tags: bool,
+    /// Folders outside of the repository media may be read from
+    pub media_dirs: Vec<PathBuf>,
+    pub math: MathMode,
+    /// LaTeX file defining the macros of the math, relative to the repository
+    pub macros: Option<PathBuf>,
+    /// Syntect theme of the fenced code, `none` leaves it unhighlighted
+    pub code_theme: String,
+    pub extensions: Extensions,
+    pub diagrams: Diagrams,
+    pub git: GitBackend,
+    pub auth: Auth,
+}
+
+impl Settings {
+    /// `macros`, else the `macros.tex` of the repository when there is one
+    pub fn macros_file(&self) -> PathBuf {
+        self.macros
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("macros.tex"))
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            heading_level: 2,
+            kind: CardKind::default(),
+            folder_tags: false,
+            media_dirs: Vec::new(),
+            math: MathMode::default(),
+            macros: None,
+            code_theme: highlight::DEFAULT_THEME.to_string(),
+            extensions: Extensions::default(),
+            diagrams: Diagrams::default(),
+            git: GitBackend::default(),
+            auth: Auth::default(),
+        }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(untagged)]
 pub enum Repo {
@@ -24,9 +203,77 @@ pub enum Repo {
         url: String,
         target: Option<String>,
         deck_name: Option<String>,
+        /// Follow this branch instead of the default one
+        branch: Option<String>,
+        tag: Option<String>,
+        rev: Option<String>,
+        #[serde(flatten)]
+        settings: Box<Settings>,
     },
 }
 
+/// KaTeX files the card templates load, each one needs those before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KatexAssets {
+    None,
+    /// Styles the HTML rendered by gencore
+    Stylesheet,
+    /// The stylesheet and the scripts rendering `$...$` in the webview
+    Scripts,
+}
+
+/// Stylesheet of KaTeX, appended to the front templates
+pub const KATEX_STYLESHEET: &str = r#"
+<link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/katex.min.css" integrity="sha384-nB0miv6/jRmo5UMMR1wu3Gz6NLsoTkbqJghGIsx//Rlm+ZU03BU6SQNC66uf4l5+" crossorigin="anonymous">"#;
+
+/// Scripts of KaTeX rendering `$...$` in the webview, appended after the stylesheet
+pub const KATEX_SCRIPTS: &str = r#"
+<script defer src="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/katex.min.js" integrity="sha384-7zkQWkzuo3B5mTepMUcHkMB5jZaolc2xDwL6VFqjFALcbeS9Ggm/Yr2r3Dy4lfFg" crossorigin="anonymous"></script>
+<script defer src="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/contrib/auto-render.min.js" integrity="sha384-43gviWU0YVjaDtb/GhzOouOXtZMP/7XUzwPTstBeZFe/+rCMvRwr4yROQP43s0Xk" crossorigin="anonymous" onload="renderMathInElement(document.body);"></script>
+<script>
+    renderMathInElement(document.body, {
+      // customised options
+      // • auto-render specific keys, e.g.:
+      delimiters: [
+          {left: '$$', right: '$$', display: true},
+          {left: '$', right: '$', display: false},
+      ],
+      // • rendering keys, e.g.:
+      throwOnError : false
+    });
+</script>
+"#;
+
+impl KatexAssets {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Stylesheet => "stylesheet",
+            Self::Scripts => "scripts",
+        }
+    }
+
+    /// HTML loading the assets, at the end of the front templates
+    pub fn html(self) -> String {
+        match self {
+            Self::None => String::new(),
+            Self::Stylesheet => KATEX_STYLESHEET.to_string(),
+            Self::Scripts => format!("{KATEX_STYLESHEET}{KATEX_SCRIPTS}"),
+        }
+    }
+}
+
+impl Config {
+    /// What the card templates have to load of KaTeX for the math of every repository
+    pub fn katex_assets(&self) -> KatexAssets {
+        self.repo
+            .values()
+            .map(|repo| repo.get_settings().math.katex_assets())
+            .max()
+            .unwrap_or(KatexAssets::None)
+    }
+}
+
 impl Repo {
     pub const fn get_url(&self) -> &String {
         match self {
@@ -49,6 +296,34 @@ impl Repo {
         }
     }
 
+    pub fn get_settings(&self) -> Settings {
+        match self {
+            Self::Object { settings, .. } => settings.as_ref().clone(),
+            Self::SimpleUrl(_) => Settings::default(),
+        }
+    }
+
+    /// What the clone follows, at most one of `branch`, `tag` and `rev` may be set
+    pub fn get_pin(&self) -> anyhow::Result<Pin> {
+        let Self::Object {
+            branch, tag, rev, ..
+        } = self
+        else {
+            return Ok(Pin::Default);
+        };
+
+        match (branch, tag, rev) {
+            (None, None, None) => Ok(Pin::Default),
+            (Some(branch), None, None) => Ok(Pin::Branch(branch.clone())),
+            (None, Some(tag), None) => Ok(Pin::Tag(tag.clone())),
+            (None, None, Some(rev)) => Ok(Pin::Rev(rev.clone())),
+            _ => Err(anyhow::anyhow!(
+                "{} sets more than one of branch, tag and rev",
+                self.get_url()
+            )),
+        }
+    }
+
     pub fn get_subfolder(&self) -> String {
         match self {
             Self::SimpleUrl(_) => String::new(),
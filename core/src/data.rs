@@ -1,17 +1,167 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+};
+
+use pyo3::{Bound, IntoPyObject, Python, types::PyString};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CardKind {
+    /// Front and back, the default
+    #[default]
+    Basic,
+    /// A second card asks the back to get the front
+    #[serde(alias = "reversed")]
+    BasicAndReversed,
+    /// Like `BasicAndReversed`, but the reverse card can be disabled per note in Anki
+    OptionalReversed,
+    /// The front holds `{{cN::...}}` deletions, the back is the extra
+    Cloze,
+}
+
+impl CardKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Basic => "basic",
+            Self::BasicAndReversed => "basic-and-reversed",
+            Self::OptionalReversed => "optional-reversed",
+            Self::Cloze => "cloze",
+        }
+    }
+
+    /// Kind given by a `.class` of the heading attributes
+    pub fn from_class(class: &str) -> Option<Self> {
+        match class {
+            "basic" => Some(Self::Basic),
+            "reversed" | "basic-and-reversed" => Some(Self::BasicAndReversed),
+            "optional-reversed" => Some(Self::OptionalReversed),
+            "cloze" => Some(Self::Cloze),
+            _ => None,
+        }
+    }
+}
+
+impl<'py> IntoPyObject<'py> for CardKind {
+    type Target = PyString;
+    type Output = Bound<'py, PyString>;
+    type Error = Infallible;
+
+    fn into_pyobject(self, py: Python<'py>) -> Result<Self::Output, Self::Error> {
+        Ok(PyString::new(py, self.as_str()))
+    }
+}
 
 #[derive(Clone, Debug, pyo3::IntoPyObject)]
 pub struct Card {
+    /// Stable identity, kept across edits of the card content, unique in its deck
+    pub id: String,
+    /// Note guid in Anki, unique across the collection, see [`Card::guid_for`]
+    pub guid: String,
+    pub kind: CardKind,
     pub front: String,
     pub back: String,
     pub hash: String,
+    pub tags: Vec<String>,
+    /// Names of the media files the card refers to
+    pub media: Vec<String>,
+    /// First and last line of the card in its file
+    pub lines: (usize, usize),
+}
+
+impl Card {
+    /// Guid of the note of the card `id` of `deck`
+    ///
+    /// Ids are only unique in their deck, and Anki merges the notes of a collection sharing a
+    /// guid. `namespace` tells apart the repositories holding decks of the same name.
+    pub fn guid_for(namespace: &str, deck: &str, id: &str) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for part in [namespace, deck, id] {
+            hasher.update(part.as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.finalize().to_hex()[..16].to_string()
+    }
+}
+
+#[derive(Clone, Debug, pyo3::IntoPyObject)]
+pub struct UpdatedCard {
+    /// Hash of the card as it is currently stored in Anki
+    pub old_hash: String,
+    pub card: Card,
+}
+
+/// Problem found in a file, reported without stopping the generation
+#[derive(Clone, Debug, PartialEq, Eq, pyo3::IntoPyObject)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeckEvent {
+    Created,
+    /// The folder is still there but has no card anymore
+    Emptied,
+    Deleted,
+    /// See `DeckOutput::renamed_from`
+    Renamed,
+}
+
+impl DeckEvent {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Emptied => "emptied",
+            Self::Deleted => "deleted",
+            Self::Renamed => "renamed",
+        }
+    }
+}
+
+impl<'py> IntoPyObject<'py> for DeckEvent {
+    type Target = PyString;
+    type Output = Bound<'py, PyString>;
+    type Error = Infallible;
+
+    fn into_pyobject(self, py: Python<'py>) -> Result<Self::Output, Self::Error> {
+        Ok(PyString::new(py, self.as_str()))
+    }
 }
 
 #[derive(Clone, Debug, Default, pyo3::IntoPyObject)]
 pub struct DeckOutput {
     pub added: Vec<Card>,
+    pub updated: Vec<UpdatedCard>,
     /// Vec of hash
     pub deleted: Vec<String>,
+    pub event: Option<DeckEvent>,
+    /// Previous name of the deck when `event` is `DeckEvent::Renamed`
+    pub renamed_from: Option<String>,
+    /// Files used by the added and updated cards
+    pub media: Media,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl DeckOutput {
+    /// Take from `media` the files used by the added and updated cards
+    pub fn collect_media(&mut self, media: &Media) {
+        let names = self
+            .added
+            .iter()
+            .chain(self.updated.iter().map(|f| &f.card))
+            .flat_map(|card| &card.media);
+
+        for name in names {
+            if let Some(data) = media.get(name) {
+                self.media.insert(name.clone(), data.clone());
+            }
+        }
+    }
 }
 
+/// Content of the media files, by their name in Anki
+pub type Media = BTreeMap<String, Vec<u8>>;
+
 pub type Output = HashMap<String, DeckOutput>;
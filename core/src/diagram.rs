@@ -0,0 +1,179 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use layout::{backends::svg::SVGWriter, gv};
+
+use crate::config::Diagrams;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagramKind {
+    Mermaid,
+    Dot,
+}
+
+impl DiagramKind {
+    /// Kind of the diagrams written in fences of `language`
+    pub fn from_language(language: &str) -> Option<Self> {
+        match language {
+            "mermaid" => Some(Self::Mermaid),
+            "dot" | "graphviz" => Some(Self::Dot),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mermaid => "mermaid",
+            Self::Dot => "dot",
+        }
+    }
+
+    /// Whether the fences of this kind are drawn, mermaid is left as code without a command
+    pub fn is_drawn(self, diagrams: &Diagrams) -> bool {
+        self == Self::Dot || !diagrams.mermaid.is_empty()
+    }
+}
+
+/// Graphviz `source` laid out by layout-rs, whose panics are reported as errors
+fn render_dot(source: &str) -> Result<Vec<u8>, String> {
+    std::panic::catch_unwind(|| layout_dot(source))
+        .unwrap_or_else(|_| Err("layout-rs cannot lay out the graph".to_string()))
+}
+
+fn layout_dot(source: &str) -> Result<Vec<u8>, String> {
+    let mut parser = gv::DotParser::new(source);
+    let graph = parser.process()?;
+
+    let mut builder = gv::GraphBuilder::new();
+    builder.visit_graph(&graph);
+    let mut visual_graph = builder.get();
+    // layout-rs panics on a graph without nodes
+    if visual_graph.num_nodes() == 0 {
+        return Err("the graph is empty".to_string());
+    }
+    let mut svg = SVGWriter::new();
+    visual_graph.do_it(false, false, false, &mut svg);
+
+    Ok(svg.finalize().into_bytes())
+}
+
+/// Everything `pipe` gives until it is closed, read from another thread
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut data);
+        }
+        data
+    })
+}
+
+/// Kill `child` and the processes it started, which may hold its pipes open
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(group) = i32::try_from(child.id()) {
+        // SAFETY: plain system call, the child leads a process group of its own
+        unsafe { libc::kill(-group, libc::SIGKILL) };
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Output of `command`, given `source` on its standard input, killed after `timeout`
+fn run_command(
+    command: &[String],
+    source: &str,
+    dir: &Path,
+    timeout: Duration,
+) -> Result<Vec<u8>, String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err("the command is empty".to_string());
+    };
+
+    let mut command = Command::new(program);
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("cannot run {program}: {error}"))?;
+
+    // Written while the output is read, a command may fill its output before reading everything
+    let stdin = child.stdin.take();
+    let input = source.as_bytes().to_vec();
+    let writer = std::thread::spawn(move || match stdin {
+        Some(mut stdin) => stdin.write_all(&input),
+        None => Ok(()),
+    });
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok(None) => {
+                kill(&mut child);
+                break Err(format!(
+                    "{program} did not finish within {} seconds",
+                    timeout.as_secs()
+                ));
+            }
+            Err(error) => {
+                kill(&mut child);
+                break Err(format!("{program} failed: {error}"));
+            }
+        }
+    };
+
+    // The pipes close with the processes, the threads end then
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    let written = writer.join();
+    if !status?.success() {
+        return Err(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&stderr).trim()
+        ));
+    }
+    // A command may draw without reading its whole input
+    match written {
+        Ok(Err(error)) if error.kind() != std::io::ErrorKind::BrokenPipe => {
+            Err(format!("cannot write to {program}: {error}"))
+        }
+        _ => Ok(stdout),
+    }
+}
+
+/// SVG of the diagram, drawn by the command of the settings when there is one, run from `dir`
+pub fn render(
+    kind: DiagramKind,
+    source: &str,
+    diagrams: &Diagrams,
+    dir: &Path,
+) -> Result<Vec<u8>, String> {
+    let command = match kind {
+        DiagramKind::Mermaid => &diagrams.mermaid,
+        DiagramKind::Dot => &diagrams.dot,
+    };
+
+    match kind {
+        DiagramKind::Dot if command.is_empty() => render_dot(source),
+        DiagramKind::Mermaid if command.is_empty() => {
+            Err("mermaid needs a command, set diagrams.mermaid in the configuration".to_string())
+        }
+        _ => run_command(command, source, dir, Duration::from_secs(diagrams.timeout)),
+    }
+}
@@ -0,0 +1,45 @@
+use crate::data::CardKind;
+
+/// Metadata at the top of a markdown file, in YAML between `---` or TOML between `+++`
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct FrontMatter {
+    pub kind: Option<CardKind>,
+    pub tags: Vec<String>,
+}
+
+impl FrontMatter {
+    /// Delimiter of the front matter block starting `input`, if any
+    pub fn delimiter(input: &str) -> Option<&'static str> {
+        let mut lines = input.lines();
+        let delimiter = match lines.next().map(str::trim_end) {
+            Some("---") => "---",
+            Some("+++") => "+++",
+            _ => return None,
+        };
+
+        lines
+            .any(|line| line.trim_end() == delimiter)
+            .then_some(delimiter)
+    }
+
+    /// Front matter of `input`, the default one when there is none
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        let Some(delimiter) = Self::delimiter(input) else {
+            return Ok(Self::default());
+        };
+
+        let content = input
+            .lines()
+            .skip(1)
+            .take_while(|line| line.trim_end() != delimiter)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if delimiter == "---" {
+            Ok(serde_yaml_ng::from_str::<Option<Self>>(&content)?.unwrap_or_default())
+        } else {
+            Ok(toml::from_str(&content)?)
+        }
+    }
+}
@@ -1,66 +1,346 @@
-use crate::{data::Card, markdown::CustomMath};
-use comrak::{Arena, Options, parse_document};
-use std::path::{Path, PathBuf};
+use crate::{
+    config::{MathMode, Settings},
+    data::{Card, CardKind, Diagnostic, Media},
+    frontmatter::FrontMatter,
+    highlight,
+    macros::Macros,
+    markdown::{CustomMath, RenderState},
+    source::Source,
+};
+use comrak::{Arena, Options, nodes::NodeValue, parse_document};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+use syntect::highlighting::Theme;
 
 pub struct CurrentPath<'a> {
+    pub source: &'a dyn Source,
+    pub settings: &'a Settings,
     pub project_path: &'a Path,
+    /// Media cannot be read from outside of it
+    pub root: &'a Path,
     pub file_path: &'a Path,
+    /// Kind of the cards of the file without one in their heading
+    pub kind: CardKind,
+    /// Tags of every card of the file
+    pub tags: Vec<String>,
+    /// Media files found while rendering the cards
+    pub media: &'a RefCell<Media>,
+    pub diagnostics: &'a RefCell<Vec<Diagnostic>>,
+    pub macros: &'a Macros,
+    /// Theme of the code blocks, checked at the first block of the generator
+    pub code_theme: &'a OnceCell<Option<&'static Theme>>,
+}
+
+#[derive(Debug, Default)]
+pub struct HeadingAttributes<'a> {
+    pub id: Option<&'a str>,
+    pub classes: Vec<&'a str>,
+}
+
+/// Split the `{#id .class}` attribute block at the end of a heading line
+pub fn split_heading_attributes(line: &str) -> (&str, HeadingAttributes<'_>) {
+    let trimmed = line.trim_end();
+    let Some(start) = trimmed
+        .strip_suffix('}')
+        .and_then(|without_brace| without_brace.rfind('{'))
+    else {
+        return (line, HeadingAttributes::default());
+    };
+
+    let mut attributes = HeadingAttributes::default();
+    for token in trimmed[start + 1..trimmed.len() - 1].split_whitespace() {
+        if let Some(id) = token.strip_prefix('#').filter(|f| !f.is_empty()) {
+            attributes.id = Some(id);
+        } else if let Some(class) = token.strip_prefix('.').filter(|f| !f.is_empty()) {
+            attributes.classes.push(class);
+        } else {
+            return (line, HeadingAttributes::default());
+        }
+    }
+
+    if attributes.id.is_none() && attributes.classes.is_empty() {
+        return (line, attributes);
+    }
+
+    (trimmed[..start].trim_end(), attributes)
+}
+
+/// Split the `#tag` tokens at the end of a heading
+pub fn split_heading_tags(heading: &str) -> (&str, Vec<&str>) {
+    let mut text = heading.trim_end();
+    let mut tags = Vec::new();
+    while let Some((rest, token)) = text.rsplit_once(char::is_whitespace) {
+        let Some(tag) = token
+            .strip_prefix('#')
+            .filter(|tag| tag.starts_with(char::is_alphabetic))
+        else {
+            break;
+        };
+
+        tags.push(tag);
+        text = rest.trim_end();
+    }
+
+    tags.reverse();
+    (text, tags)
+}
+
+/// `N` of every `{{cN::...}}` deletion written in `input`
+pub fn cloze_numbers(input: &str) -> BTreeSet<usize> {
+    input
+        .match_indices("{{c")
+        .filter_map(|(i, _)| {
+            let rest = &input[i + 3..];
+            let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+            rest[digits..]
+                .starts_with("::")
+                .then(|| rest[..digits].parse().ok())
+                .flatten()
+        })
+        .collect()
 }
 
 pub struct CardGenerator<'a> {
     content: String,
+    lines: (usize, usize),
     paths: &'a CurrentPath<'a>,
 }
 
 impl<'a> CardGenerator<'a> {
-    pub const fn new(content: String, paths: &'a CurrentPath<'a>) -> Self {
-        Self { content, paths }
+    pub const fn new(content: String, lines: (usize, usize), paths: &'a CurrentPath<'a>) -> Self {
+        Self {
+            content,
+            lines,
+            paths,
+        }
     }
 
-    fn to_html(&self, input: &str) -> anyhow::Result<String> {
-        let options = Options {
+    fn heading_line(&self) -> &str {
+        self.content.lines().next().unwrap_or_default()
+    }
+
+    /// Heading line without its tags and attributes
+    fn heading_text(&self) -> &str {
+        let (heading, _) = split_heading_attributes(self.heading_line());
+        split_heading_tags(heading).0
+    }
+
+    /// Content of the card without the heading tags and attributes
+    fn body(&self) -> String {
+        let heading = self.heading_line();
+        format!("{}{}", self.heading_text(), &self.content[heading.len()..])
+    }
+
+    /// Tags of the file and of the heading, Anki tags cannot hold spaces
+    fn tags(&self) -> Vec<String> {
+        let (heading, _) = split_heading_attributes(self.heading_line());
+        let (_, heading_tags) = split_heading_tags(heading);
+        self.paths
+            .tags
+            .iter()
+            .map(String::as_str)
+            .chain(heading_tags)
+            .map(|tag| tag.split_whitespace().collect::<Vec<_>>().join("_"))
+            .filter(|tag| !tag.is_empty())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn generate_id(&self) -> String {
+        let (_, attributes) = split_heading_attributes(self.heading_line());
+        if let Some(id) = attributes.id {
+            return id.to_string();
+        }
+        let heading = self.heading_text();
+
+        let file = self
+            .paths
+            .file_path
+            .strip_prefix(self.paths.project_path)
+            .unwrap_or(self.paths.file_path);
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(heading.trim().as_bytes());
+        hasher.finalize().to_hex().as_str().to_string()
+    }
+
+    fn options(&self) -> Options<'static> {
+        let extensions = &self.paths.settings.extensions;
+        Options {
             extension: comrak::options::Extension {
                 math_dollars: true,
                 math_code: true,
+                highlight: true,
+                table: extensions.table,
+                strikethrough: extensions.strikethrough,
+                tasklist: extensions.tasklist,
+                footnotes: extensions.footnotes,
+                autolink: extensions.autolink,
+                alerts: extensions.alerts,
                 ..Default::default()
             },
             ..Default::default()
-        };
+        }
+    }
+
+    /// HTML of `input`, `state` is carried from one field of the card to the next
+    fn to_html(&self, input: &str, state: &mut RenderState) -> anyhow::Result<String> {
+        let options = self.options();
         let arena = Arena::new();
         let document = parse_document(&arena, input, &options);
         let mut output = String::new();
 
-        CustomMath::format_document(document, &options, &mut output, self.paths)?;
+        // The formatter ties its state to the document, so it works on a copy
+        let user = RenderState {
+            paths: self.paths,
+            next_cloze: state.next_cloze,
+            media: std::mem::take(&mut state.media),
+            embedded: std::mem::take(&mut state.embedded),
+            line: state.line,
+            macros: std::mem::take(&mut state.macros),
+        };
+        let user = CustomMath::format_document(document, &options, &mut output, user)?;
+        state.next_cloze = user.next_cloze;
+        state.media = user.media;
+        state.embedded = user.embedded;
+        state.macros = user.macros;
 
         Ok(output.trim().to_string())
     }
 
-    fn transform_to_html(&self, card: Card) -> anyhow::Result<Card> {
-        let front = self.to_html(&card.front)?;
-        let back = self.to_html(&card.back)?;
-        Ok(Card {
+    /// The card in HTML, with the macros its math uses
+    fn transform_to_html(
+        &self,
+        card: Card,
+        first_cloze: usize,
+    ) -> anyhow::Result<(Card, BTreeSet<String>)> {
+        let mut state = RenderState {
+            paths: self.paths,
+            next_cloze: first_cloze,
+            media: Vec::new(),
+            embedded: Vec::new(),
+            line: self.lines.0,
+            macros: BTreeSet::new(),
+        };
+        let front = self.to_html(&card.front, &mut state)?;
+        let back = self.to_html(&card.back, &mut state)?;
+        state.media.sort();
+        state.media.dedup();
+        let card = Card {
             front,
             back,
+            media: state.media,
             ..card
-        })
+        };
+
+        Ok((card, state.macros))
     }
 
-    fn generate_hash(&self) -> String {
+    /// Highest `N` of the `{{cN::...}}` deletions of `body`, those written in code do not count
+    fn max_cloze_number(&self, body: &str) -> usize {
+        let arena = Arena::new();
+        let document = parse_document(&arena, body, &self.options());
+        document
+            .descendants()
+            .filter_map(|node| match &node.data().value {
+                NodeValue::Text(text) => cloze_numbers(text).last().copied(),
+                NodeValue::Math(math) => cloze_numbers(&math.literal).last().copied(),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Cards with `{{c1::...}}` or `==...==` deletions are cloze cards
+    fn is_cloze(&self, body: &str) -> bool {
+        if self.max_cloze_number(body) > 0 {
+            return true;
+        }
+
+        let arena = Arena::new();
+        let document = parse_document(&arena, body, &self.options());
+        document
+            .descendants()
+            .any(|node| matches!(node.data().value, NodeValue::Highlight))
+    }
+
+    fn generate_hash(
+        &self,
+        kind: CardKind,
+        tags: &[String],
+        media: &[String],
+        macros: &BTreeSet<String>,
+    ) -> String {
         let mut hasher = blake3::Hasher::new();
         hasher.update(self.content.trim().as_bytes());
+        if kind != CardKind::Basic {
+            hasher.update(kind.as_str().as_bytes());
+        }
+        if self.paths.settings.math != MathMode::Client {
+            hasher.update(self.paths.settings.math.as_str().as_bytes());
+        }
+        if self.paths.settings.code_theme != highlight::DEFAULT_THEME {
+            hasher.update(b"\0");
+            hasher.update(self.paths.settings.code_theme.as_bytes());
+        }
+        let disabled = self.paths.settings.extensions.disabled();
+        if !disabled.is_empty() {
+            hasher.update(b"\0");
+            hasher.update(disabled.join(" ").as_bytes());
+        }
+        if !tags.is_empty() {
+            hasher.update(b"\0");
+            hasher.update(tags.join(" ").as_bytes());
+        }
+        // Media are named after their content, so an edited file changes the hash
+        if !media.is_empty() {
+            hasher.update(b"\0");
+            hasher.update(media.join(" ").as_bytes());
+        }
+        // Editing a macro changes the cards using it, and only them
+        for (name, definition) in macros
+            .iter()
+            .filter_map(|name| Some((name, self.paths.macros.get(name)?)))
+        {
+            hasher.update(b"\0\\");
+            hasher.update(name.as_bytes());
+            hasher.update(&definition.arguments.to_le_bytes());
+            hasher.update(definition.default.as_deref().unwrap_or_default().as_bytes());
+            hasher.update(definition.body.as_bytes());
+        }
         hasher.finalize().to_hex().as_str().to_string()
     }
 
-    fn split_extended(&self) -> anyhow::Result<(String, String)> {
-        let Some((front, back)) = self.content.split_once('%') else {
+    /// Kind set by the heading, else by the file or the repository
+    fn kind(&self, body: &str) -> CardKind {
+        if self.is_cloze(body) {
+            return CardKind::Cloze;
+        }
+
+        let (_, attributes) = split_heading_attributes(self.heading_line());
+        attributes
+            .classes
+            .iter()
+            .find_map(|class| CardKind::from_class(class))
+            .unwrap_or(self.paths.kind)
+    }
+
+    fn split_extended(body: &str) -> anyhow::Result<(String, String)> {
+        let Some((front, back)) = body.split_once('%') else {
             return Err(anyhow::anyhow!("This card isn't extended"));
         };
 
         Ok((front.to_string(), back.to_string()))
     }
 
-    fn split_basic(&self) -> anyhow::Result<(String, String)> {
-        let lines = self.content.lines().collect::<Vec<_>>();
+    fn split_basic(body: &str) -> anyhow::Result<(String, String)> {
+        let lines = body.lines().collect::<Vec<_>>();
         let front = lines[0].to_string();
         let back = lines[1..].join("\n");
 
@@ -72,72 +352,303 @@ impl<'a> CardGenerator<'a> {
     }
 
     pub fn generate(&self) -> anyhow::Result<Card> {
-        let (front, back) = if self.is_extends() {
-            self.split_extended()?
-        } else {
-            self.split_basic()?
+        let body = self.body();
+        let kind = self.kind(&body);
+        let tags = self.tags();
+
+        let (front, back) = match kind {
+            _ if self.is_extends() => Self::split_extended(&body)?,
+            CardKind::Cloze => (body.clone(), String::new()),
+            _ => Self::split_basic(&body)?,
         };
 
-        self.transform_to_html(Card {
-            front,
-            back,
-            hash: self.generate_hash(),
-        })
+        let (mut card, macros) = self.transform_to_html(
+            Card {
+                id: self.generate_id(),
+                guid: String::new(),
+                kind,
+                front,
+                back,
+                hash: String::new(),
+                tags,
+                media: Vec::new(),
+                lines: self.lines,
+            },
+            self.max_cloze_number(&body) + 1,
+        )?;
+        card.hash = self.generate_hash(kind, &card.tags, &card.media, &macros);
+
+        Ok(card)
     }
 }
 
-pub fn get_md_of_folder(path: &Path) -> Vec<PathBuf> {
-    std::fs::read_dir(path)
+/// Markdown files of `path`, sorted so that duplicate ids are renamed the same way every time
+pub fn get_md_of_folder(source: &dyn Source, path: &Path) -> Vec<PathBuf> {
+    let mut files = source
+        .files(path)
         .into_iter()
-        .flatten()
-        .flatten()
-        .filter(|dir_entry| dir_entry.file_type().map(|f| f.is_file()).unwrap_or(false))
-        .map(|f| f.path())
         .filter(|path| path.extension().is_some_and(|ext| ext == "md"))
-        .collect()
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Markdown of a card, with the lines it spans in its file
+pub struct Segment {
+    pub content: String,
+    pub lines: (usize, usize),
 }
 
 pub struct Generator<'a> {
+    pub source: &'a dyn Source,
+    pub settings: &'a Settings,
     pub subproject_path: &'a Path,
+    root: &'a Path,
+    media: RefCell<Media>,
+    diagnostics: RefCell<Vec<Diagnostic>>,
+    /// Markdown files that could not be read, their cards are missing from the output
+    unreadable: RefCell<Vec<PathBuf>>,
+    macros: OnceCell<Macros>,
+    code_theme: OnceCell<Option<&'static Theme>>,
 }
 
-impl Generator<'_> {
-    fn skip_until_first_card(input: &str) -> &str {
-        let mut offset = 0;
-        for i in input.lines() {
-            if i.starts_with("##") {
-                break;
-            }
+impl<'a> Generator<'a> {
+    pub fn new(source: &'a dyn Source, settings: &'a Settings, subproject_path: &'a Path) -> Self {
+        Self {
+            source,
+            settings,
+            subproject_path,
+            root: subproject_path,
+            media: RefCell::default(),
+            diagnostics: RefCell::default(),
+            unreadable: RefCell::default(),
+            macros: OnceCell::new(),
+            code_theme: OnceCell::new(),
+        }
+    }
+
+    /// Allow media from anywhere in `root` instead of only the deck folder
+    pub const fn with_root(mut self, root: &'a Path) -> Self {
+        self.root = root;
+        self
+    }
+
+    /// Media files used by the cards generated so far
+    pub fn take_media(&self) -> Media {
+        self.media.take()
+    }
+
+    pub fn take_diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.take()
+    }
 
-            offset += i.len();
-        }
-
-        &input[offset..]
-    }
-    pub fn generate_card_from_input(&self, input: &str, path: &Path) -> Vec<Card> {
-        Self::skip_until_first_card(input)
-            .split("##")
-            .filter(|f| !f.trim_end().is_empty())
-            .map(|f| format!("##{}", f.trim_end()))
-            .flat_map(|f| {
-                CardGenerator::new(
-                    f,
-                    &CurrentPath {
-                        project_path: self.subproject_path,
-                        file_path: path,
-                    },
-                )
-                .generate()
+    pub fn take_unreadable(&self) -> Vec<PathBuf> {
+        self.unreadable.take()
+    }
+
+    /// Macros of the file set in the settings, read once from the source
+    fn macros(&self) -> &Macros {
+        self.macros.get_or_init(|| {
+            let path = self.settings.macros_file();
+            let full_path = self.root.join(&path);
+            let content = match self.source.read_to_string(&full_path) {
+                Ok(content) => content,
+                Err(_) if self.settings.macros.is_none() => return Macros::default(),
+                Err(error) => {
+                    self.report(&path, 1, format!("Cannot read the macros: {error}"));
+                    return Macros::default();
+                }
+            };
+
+            Macros::parse(&content).unwrap_or_else(|error| {
+                self.report(&path, 1, format!("Invalid macros: {error}"));
+                Macros::default()
             })
-            .collect::<Vec<_>>()
+        })
+    }
+
+    /// Add a diagnostic for `line` of `file`, shown relative to the repository
+    fn report(&self, file: &Path, line: usize, message: String) {
+        self.diagnostics.borrow_mut().push(Diagnostic {
+            file: file
+                .strip_prefix(self.root)
+                .unwrap_or(file)
+                .display()
+                .to_string(),
+            line,
+            message,
+        });
     }
-    pub fn generate_card_from_folder(&self) -> Vec<Card> {
-        get_md_of_folder(self.subproject_path)
+
+    /// Split `input` at the headings of the configured level, anything before the first one is skipped
+    pub fn segment(&self, input: &str) -> Vec<Segment> {
+        let level = self.settings.heading_level;
+        let options = Options {
+            extension: comrak::options::Extension {
+                front_matter_delimiter: FrontMatter::delimiter(input).map(String::from),
+                footnotes: self.settings.extensions.footnotes,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let arena = Arena::new();
+        let document = parse_document(&arena, input, &options);
+        let lines = input.lines().collect::<Vec<_>>();
+
+        // Definitions are often gathered at the end of the file, each card gets those it refers to
+        let footnotes = document
+            .children()
+            .filter_map(|node| {
+                let ast = node.data();
+                let NodeValue::FootnoteDefinition(ref definition) = ast.value else {
+                    return None;
+                };
+
+                Some((definition.name.clone(), ast.sourcepos))
+            })
+            .collect::<Vec<_>>();
+
+        let headings = document
+            .children()
+            .filter_map(|node| {
+                let ast = node.data();
+                let NodeValue::Heading(ref heading) = ast.value else {
+                    return None;
+                };
+
+                (heading.level <= level).then(|| (ast.sourcepos, heading.level, heading.setext))
+            })
+            .collect::<Vec<_>>();
+
+        headings
             .iter()
-            .flat_map(|f| {
-                let content = std::fs::read_to_string(f).unwrap();
-                self.generate_card_from_input(&content, f.as_path())
+            .enumerate()
+            .filter(|(_, (_, heading_level, _))| *heading_level == level)
+            .map(|(i, (sourcepos, _, setext))| {
+                let start = sourcepos.start.line;
+                let end = headings
+                    .get(i + 1)
+                    .map_or(lines.len(), |(next, _, _)| next.start.line - 1);
+
+                let body = &lines[sourcepos.end.line.min(end)..end];
+                let heading = if *setext {
+                    let text = lines[start - 1..sourcepos.end.line - 1]
+                        .iter()
+                        .map(|f| f.trim())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    format!("{} {text}", "#".repeat(level.into()))
+                } else {
+                    lines[start - 1].to_string()
+                };
+
+                let content = std::iter::once(heading.as_str())
+                    .chain(body.iter().copied())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let mut content = content.trim_end().to_string();
+                let end = sourcepos.end.line + content.lines().count().max(1) - 1;
+
+                for (name, definition) in &footnotes {
+                    let inside = (start..=end).contains(&definition.start.line);
+                    if !inside && content.contains(&format!("[^{name}]")) {
+                        content.push_str("\n\n");
+                        content.push_str(
+                            &lines[definition.start.line - 1..definition.end.line].join("\n"),
+                        );
+                    }
+                }
+
+                Segment {
+                    content,
+                    lines: (start, end),
+                }
             })
             .collect()
     }
+
+    /// Cards of `input`, tagged with `deck` when folder tags are enabled
+    pub fn generate_card_from_input(
+        &self,
+        input: &str,
+        path: &Path,
+        deck: Option<&str>,
+    ) -> Vec<Card> {
+        let front_matter = FrontMatter::parse(input).unwrap_or_else(|error| {
+            self.report(
+                path,
+                1,
+                format!("Invalid front matter, the file uses the defaults: {error}"),
+            );
+            FrontMatter::default()
+        });
+        let mut tags = front_matter.tags;
+        if self.settings.folder_tags {
+            tags.extend(deck.map(String::from));
+        }
+
+        let paths = CurrentPath {
+            source: self.source,
+            settings: self.settings,
+            project_path: self.subproject_path,
+            root: self.root,
+            file_path: path,
+            kind: front_matter.kind.unwrap_or(self.settings.kind),
+            tags,
+            media: &self.media,
+            diagnostics: &self.diagnostics,
+            macros: self.macros(),
+            code_theme: &self.code_theme,
+        };
+
+        self.segment(input)
+            .into_iter()
+            .flat_map(|segment| {
+                CardGenerator::new(segment.content, segment.lines, &paths).generate()
+            })
+            .collect::<Vec<_>>()
+    }
+
+    /// Cards of every file of the deck, an id already used in the deck, in this file or another, gets a suffix
+    pub fn generate_card_from_folder(&self, deck: &str) -> Vec<Card> {
+        let mut first_files: HashMap<String, PathBuf> = HashMap::new();
+        let mut cards = Vec::new();
+        for f in get_md_of_folder(self.source, self.subproject_path) {
+            let content = match self.source.read_to_string(&f) {
+                Ok(content) => content,
+                Err(error) => {
+                    self.report(
+                        &f,
+                        1,
+                        format!("Cannot read the file, its cards are skipped: {error}"),
+                    );
+                    self.unreadable.borrow_mut().push(f);
+                    continue;
+                }
+            };
+            for mut card in self.generate_card_from_input(&content, f.as_path(), Some(deck)) {
+                if let Some(first_file) = first_files.get(&card.id) {
+                    let count = (1..)
+                        .find(|count| !first_files.contains_key(&format!("{}-{count}", card.id)))
+                        .unwrap_or_default();
+                    let id = format!("{}-{count}", card.id);
+                    let first_file = first_file.strip_prefix(self.root).unwrap_or(first_file);
+                    self.report(
+                        &f,
+                        card.lines.0,
+                        format!(
+                            "The id {} is already used in {}, this card is {id}",
+                            card.id,
+                            first_file.display()
+                        ),
+                    );
+                    card.id = id;
+                }
+                first_files.insert(card.id.clone(), f.clone());
+                cards.push(card);
+            }
+        }
+
+        cards
+    }
 }
@@ -0,0 +1,132 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::config::Auth;
+
+use super::GitError;
+
+/// What git and ssh print when the remote refuses the credentials, or when there are none
+const FAILURES: [&str; 6] = [
+    "Authentication failed",
+    "Permission denied (publickey",
+    "could not read Username",
+    "could not read Password",
+    "terminal prompts disabled",
+    "HTTP Basic: Access denied",
+];
+
+/// Whether `stderr` of git tells that the remote refused to authenticate
+pub fn is_failure(stderr: &str) -> bool {
+    FAILURES.iter().any(|failure| stderr.contains(failure))
+}
+
+/// Private key of `auth`, checked before any connection
+pub fn ssh_key(auth: &Auth) -> Result<Option<&Path>, GitError> {
+    match &auth.ssh_key {
+        Some(key) if !key.is_file() => Err(GitError::KeyNotFound(key.clone())),
+        key => Ok(key.as_deref()),
+    }
+}
+
+/// Keys `ssh` offers when none is configured, in its order, from `~/.ssh`
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Credential offered to an SSH remote by the builtin backend
+#[derive(Debug, PartialEq, Eq)]
+pub enum SshIdentity {
+    Agent,
+    Key(PathBuf),
+}
+
+/// What libgit2 offers over SSH, one after the other as the remote refuses them
+///
+/// Only the key of `auth` when it sets one, else the agent when it runs and the default keys
+/// in the `.ssh` folder of `home`, as `ssh` would without any configuration.
+pub fn ssh_identities(
+    auth: &Auth,
+    agent: bool,
+    home: Option<&Path>,
+) -> Result<Vec<SshIdentity>, GitError> {
+    if let Some(key) = ssh_key(auth)? {
+        return Ok(vec![SshIdentity::Key(key.to_path_buf())]);
+    }
+
+    let agent = agent.then_some(SshIdentity::Agent);
+    let keys = home
+        .into_iter()
+        .flat_map(|home| DEFAULT_KEYS.map(|name| home.join(".ssh").join(name)))
+        .filter(|key| key.is_file())
+        .map(SshIdentity::Key);
+    Ok(agent.into_iter().chain(keys).collect())
+}
+
+/// Token of `auth`, read from its environment variable
+pub fn token(auth: &Auth) -> Result<Option<String>, GitError> {
+    let Some(name) = &auth.token_env else {
+        return Ok(None);
+    };
+
+    match std::env::var(name) {
+        Ok(token) if !token.is_empty() => Ok(Some(token)),
+        _ => Err(GitError::TokenNotSet(name.clone())),
+    }
+}
+
+/// User sent with the token, when the url has none
+pub fn username<'a>(auth: &'a Auth, from_url: Option<&'a str>) -> &'a str {
+    auth.username.as_deref().or(from_url).unwrap_or("git")
+}
+
+/// Username and password `helper` gives for `url`, following the credential protocol of git
+pub fn fill(helper: &str, url: &str) -> Result<Option<(String, String)>, GitError> {
+    let command = match helper.strip_prefix('!') {
+        Some(shell) => shell.to_string(),
+        None if Path::new(helper).is_absolute() => helper.to_string(),
+        None => format!("git credential-{helper}"),
+    };
+    let (protocol, rest) = url.split_once("://").unwrap_or(("https", url));
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (user, host) = match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, authority),
+    };
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{command} get"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let mut request = format!("protocol={protocol}\nhost={host}\n");
+        if let Some(user) = user {
+            request.push_str(&format!("username={user}\n"));
+        }
+        request.push('\n');
+        // A helper may exit without reading its input
+        let _ = stdin.write_all(request.as_bytes());
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(GitError::Command {
+            command: format!("credential helper {helper}"),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    let mut username = user.map(str::to_string);
+    let mut password = None;
+    for line in String::from_utf8(output.stdout)?.lines() {
+        match line.split_once('=') {
+            Some(("username", value)) => username = Some(value.to_string()),
+            Some(("password", value)) => password = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Ok(password.map(|password| (username.unwrap_or_else(|| "git".to_string()), password)))
+}
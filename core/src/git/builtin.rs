@@ -0,0 +1,378 @@
+use std::path::{Path, PathBuf};
+
+use std::cell::Cell;
+
+use git2::{
+    Cred, CredentialType, Delta, DiffOptions, ErrorClass, ErrorCode, FetchOptions, ObjectType, Oid,
+    RemoteCallbacks, Repository, StatusOptions, Tree, TreeWalkMode, TreeWalkResult,
+    build::CheckoutBuilder, build::RepoBuilder,
+};
+
+use crate::config::Auth;
+
+use super::{
+    Backend, Change, EntryKind, GitError, Pin, REMOTE, TreeEntry, auth, is_missing_commit,
+};
+
+/// libgit2, without any process or system configuration
+pub struct Builtin {
+    repo: PathBuf,
+    auth: Auth,
+}
+
+/// Options answering the credential requests of libgit2 from `auth`
+fn fetch_options(auth: &Auth) -> Result<FetchOptions<'_>, GitError> {
+    let identities = auth::ssh_identities(
+        auth,
+        std::env::var_os("SSH_AUTH_SOCK").is_some(),
+        std::env::var_os("HOME").as_deref().map(Path::new),
+    )?;
+    let token = auth::token(auth)?;
+    // libgit2 asks again as long as the remote refuses, each kind is only tried once
+    let tried = Cell::new(CredentialType::empty());
+    let next_identity = Cell::new(0);
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, from_url, allowed| {
+        let allowed = allowed - tried.get();
+        // Asked first over SSH when the url names no user
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(from_url.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY)
+            && let Some(identity) = identities.get(next_identity.get())
+        {
+            next_identity.set(next_identity.get() + 1);
+            let username = from_url.unwrap_or("git");
+            return match identity {
+                auth::SshIdentity::Agent => Cred::ssh_key_from_agent(username),
+                auth::SshIdentity::Key(key) => Cred::ssh_key(username, None, key, None),
+            };
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            tried.set(tried.get() | CredentialType::USER_PASS_PLAINTEXT);
+            if let Some(token) = &token {
+                return Cred::userpass_plaintext(auth::username(auth, from_url), token);
+            }
+            if let Some(helper) = &auth.credential_helper
+                && let Ok(Some((username, password))) = auth::fill(helper, url)
+            {
+                return Cred::userpass_plaintext(&username, &password);
+            }
+        }
+        Err(git2::Error::new(
+            ErrorCode::Auth,
+            ErrorClass::Net,
+            "no accepted credentials",
+        ))
+    });
+
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    Ok(options)
+}
+
+/// Refused credentials as [`GitError::Auth`], apart from the other errors
+fn network(error: git2::Error) -> GitError {
+    if error.code() == ErrorCode::Auth {
+        GitError::Auth(error.message().to_string())
+    } else {
+        GitError::Libgit2(error)
+    }
+}
+
+/// A commit the remote refused to send as [`GitError::Unreachable`], apart from the other errors
+fn missing_commit(error: git2::Error, commit: &str) -> GitError {
+    if error.code() == ErrorCode::NotFound || is_missing_commit(error.message()) {
+        GitError::Unreachable(commit.to_string())
+    } else {
+        network(error)
+    }
+}
+
+impl Builtin {
+    pub fn new(repo: &str, auth: Auth) -> Self {
+        Self {
+            repo: PathBuf::from(repo),
+            auth,
+        }
+    }
+
+    pub fn clone(url: &str, path: &Path, auth: &Auth) -> Result<(), GitError> {
+        let mut fetch = fetch_options(auth)?;
+        // libgit2 cannot fetch shallow from a local repository, where depth saves nothing anyway
+        let local = url.starts_with("file://") || Path::new(url).exists();
+        if !local {
+            fetch.depth(1);
+        }
+        RepoBuilder::new()
+            .fetch_options(fetch)
+            .clone(url, path)
+            .map_err(network)?;
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Repository, GitError> {
+        Ok(Repository::open(&self.repo)?)
+    }
+
+    fn commit_tree<'r>(repo: &'r Repository, commit: &str) -> Result<Tree<'r>, GitError> {
+        let object = repo
+            .revparse_single(commit)
+            .map_err(|_| GitError::UnknownRevision(commit.to_string()))?;
+        Ok(object.peel_to_tree()?)
+    }
+
+    fn not_found(commit: &str, path: &Path) -> GitError {
+        GitError::NotFound {
+            commit: commit.to_string(),
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Backend for Builtin {
+    fn rev_parse(&self, rev: &str) -> Result<String, GitError> {
+        let repo = self.open()?;
+        let commit = repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|_| GitError::UnknownRevision(rev.to_string()))?;
+
+        Ok(commit.id().to_string())
+    }
+
+    fn fetch(&self, pin: &Pin) -> Result<String, GitError> {
+        let repo = self.open()?;
+        let mut remote = repo.find_remote(REMOTE)?;
+        let mut options = fetch_options(&self.auth)?;
+        let target = match pin {
+            Pin::Default => {
+                let head = repo.head()?;
+                let branch = head.name().unwrap_or("HEAD").to_string();
+                match repo.branch_upstream_name(&branch) {
+                    Ok(upstream) => upstream.as_str().unwrap_or_default().to_string(),
+                    Err(_) if !head.is_branch() => format!("refs/remotes/{REMOTE}/HEAD"),
+                    Err(_) => return Err(GitError::NoUpstream(branch)),
+                }
+            }
+            Pin::Branch(branch) => {
+                let target = format!("refs/remotes/{REMOTE}/{branch}");
+                remote
+                    .fetch(
+                        &[format!("+refs/heads/{branch}:{target}")],
+                        Some(&mut options),
+                        None,
+                    )
+                    .map_err(network)?;
+                target
+            }
+            Pin::Tag(tag) => {
+                let target = format!("refs/tags/{tag}");
+                remote
+                    .fetch(&[format!("+{target}:{target}")], Some(&mut options), None)
+                    .map_err(network)?;
+                target
+            }
+            Pin::Rev(rev) => {
+                if repo.revparse_single(rev).is_err() {
+                    // Servers may refuse a commit id, which is then found on a branch or tag
+                    if let Err(error) = remote.fetch(&[rev.as_str()], Some(&mut options), None) {
+                        match missing_commit(error, rev) {
+                            GitError::Unreachable(_) => {}
+                            error => return Err(error),
+                        }
+                    }
+                }
+                if repo.revparse_single(rev).is_err() {
+                    let refspecs = [
+                        format!("+refs/heads/*:refs/remotes/{REMOTE}/*"),
+                        "+refs/tags/*:refs/tags/*".to_string(),
+                    ];
+                    remote
+                        .fetch(&refspecs, Some(&mut options), None)
+                        .map_err(network)?;
+                }
+                // The tips only bring the last commits into a shallow clone, an older rev needs the history
+                if repo.revparse_single(rev).is_err() && repo.is_shallow() {
+                    let mut options = fetch_options(&self.auth)?;
+                    options.depth(i32::MAX);
+                    remote
+                        .fetch::<&str>(&[], Some(&mut options), None)
+                        .map_err(network)?;
+                }
+                drop(remote);
+                return self.rev_parse(rev);
+            }
+        };
+        if *pin == Pin::Default {
+            remote
+                .fetch::<&str>(&[], Some(&mut options), None)
+                .map_err(network)?;
+        }
+
+        let commit = repo
+            .revparse_single(&target)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|_| GitError::UnknownRevision(target.clone()))?;
+        Ok(commit.id().to_string())
+    }
+
+    fn fetch_commit(&self, commit: &str) -> Result<(), GitError> {
+        let repo = self.open()?;
+        let mut options = fetch_options(&self.auth)?;
+        if repo.is_shallow() {
+            options.depth(1);
+        }
+        repo.find_remote(REMOTE)?
+            .fetch(&[commit], Some(&mut options), None)
+            .map_err(|error| missing_commit(error, commit))?;
+        Ok(())
+    }
+
+    fn set_ref(&self, name: &str, commit: &str) -> Result<(), GitError> {
+        let repo = self.open()?;
+        repo.reference(name, Oid::from_str(commit)?, true, "gencore: sync")?;
+        Ok(())
+    }
+
+    fn local_changes(&self) -> Result<Vec<PathBuf>, GitError> {
+        let repo = self.open()?;
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .include_ignored(false);
+
+        Ok(repo
+            .statuses(Some(&mut options))?
+            .iter()
+            .filter(|entry| !entry.status().is_ignored())
+            .filter_map(|entry| entry.path().map(PathBuf::from))
+            .collect())
+    }
+
+    fn fast_forward(&self, commit: &str) -> Result<bool, GitError> {
+        let repo = self.open()?;
+        let head = repo.head()?;
+        let from = head.peel_to_commit()?.id();
+        let to = Oid::from_str(commit)?;
+        if from == to || !repo.graph_descendant_of(to, from)? {
+            return Ok(false);
+        }
+
+        // A safe checkout refuses to overwrite local edits, before the branch moves
+        let target = repo.find_object(to, Some(ObjectType::Commit))?;
+        repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe()))?;
+        let branch = head.name().unwrap_or("HEAD");
+        repo.reference(branch, to, true, "gencore: fast-forward")?;
+
+        Ok(true)
+    }
+
+    fn checkout(&self, commit: &str) -> Result<(), GitError> {
+        let repo = self.open()?;
+        let target = repo.find_object(Oid::from_str(commit)?, Some(ObjectType::Commit))?;
+        repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe()))?;
+        repo.set_head_detached(target.id())?;
+        Ok(())
+    }
+
+    fn is_detached(&self) -> Result<bool, GitError> {
+        Ok(self.open()?.head_detached()?)
+    }
+
+    fn diff(&self, from_commit: &str, to_commit: &str) -> Result<Vec<Change>, GitError> {
+        let repo = self.open()?;
+        let old_tree = Self::commit_tree(&repo, from_commit)?;
+        let new_tree = Self::commit_tree(&repo, to_commit)?;
+        let diff = repo.diff_tree_to_tree(
+            Some(&old_tree),
+            Some(&new_tree),
+            Some(DiffOptions::new().ignore_submodules(false)),
+        )?;
+
+        Ok(diff
+            .deltas()
+            .map(|delta| {
+                let old_path = delta.old_file().path().map(Path::to_path_buf);
+                let new_path = delta.new_file().path().map(Path::to_path_buf);
+                match delta.status() {
+                    Delta::Added => Change {
+                        old_path: None,
+                        new_path,
+                    },
+                    Delta::Deleted => Change {
+                        old_path,
+                        new_path: None,
+                    },
+                    _ => Change { old_path, new_path },
+                }
+            })
+            .collect())
+    }
+
+    fn ls_tree(&self, commit: &str, dir: &Path) -> Result<Vec<TreeEntry>, GitError> {
+        let repo = self.open()?;
+        let root = Self::commit_tree(&repo, commit)?;
+        let tree = if dir.as_os_str().is_empty() {
+            root
+        } else {
+            root.get_path(dir)
+                .and_then(|entry| entry.to_object(&repo))
+                .and_then(|object| object.peel_to_tree())
+                .map_err(|_| Self::not_found(commit, dir))?
+        };
+
+        Ok(tree
+            .iter()
+            .filter_map(|entry| {
+                Some(TreeEntry {
+                    kind: EntryKind::from_mode(entry.filemode())?,
+                    path: dir.join(entry.name()?),
+                })
+            })
+            .collect())
+    }
+
+    fn ls_dirs(&self, commit: &str) -> Result<Vec<String>, GitError> {
+        let repo = self.open()?;
+        let tree = Self::commit_tree(&repo, commit)?;
+        let mut dirs = Vec::new();
+        tree.walk(TreeWalkMode::PreOrder, |parent, entry| {
+            if entry.kind() == Some(ObjectType::Tree)
+                && let Some(name) = entry.name()
+            {
+                dirs.push(format!("{parent}{name}"));
+            }
+            TreeWalkResult::Ok
+        })?;
+
+        Ok(dirs)
+    }
+
+    fn object_kind(&self, commit: &str, path: &Path) -> Result<EntryKind, GitError> {
+        if path.as_os_str().is_empty() {
+            return Ok(EntryKind::Dir);
+        }
+
+        let repo = self.open()?;
+        let entry = Self::commit_tree(&repo, commit)?
+            .get_path(path)
+            .map_err(|_| Self::not_found(commit, path))?;
+
+        EntryKind::from_mode(entry.filemode()).ok_or_else(|| Self::not_found(commit, path))
+    }
+
+    fn cat_file(&self, commit: &str, path: &Path) -> Result<Vec<u8>, GitError> {
+        let repo = self.open()?;
+        let entry = Self::commit_tree(&repo, commit)?
+            .get_path(path)
+            .map_err(|_| Self::not_found(commit, path))?;
+        let blob = repo
+            .find_blob(entry.id())
+            .map_err(|_| Self::not_found(commit, path))?;
+
+        Ok(blob.content().to_vec())
+    }
+}
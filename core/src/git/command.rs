@@ -0,0 +1,382 @@
+use std::path::{Path, PathBuf};
+
+use crate::config::Auth;
+
+use super::{
+    Backend, Change, EntryKind, GitError, Pin, REMOTE, TreeEntry, auth, is_missing_commit,
+};
+
+/// The `git` program, for the users who need their system configuration
+pub struct Command {
+    exe: String,
+    repo: PathBuf,
+    auth: Auth,
+}
+
+/// Quoted for the shell git runs `core.sshCommand` and the credential helpers with
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Pass the credentials of `auth` to `git`, which reads its configuration from `dir`
+fn authenticate(git: &mut std::process::Command, auth: &Auth, dir: &Path) -> Result<(), GitError> {
+    // ssh must not prompt either, for a passphrase or an unknown host
+    let ssh = std::env::var("GIT_SSH_COMMAND")
+        .ok()
+        .filter(|ssh| !ssh.is_empty())
+        .or_else(|| {
+            let mut config = std::process::Command::new("git");
+            config
+                .current_dir(dir)
+                .args(["config", "--get", "core.sshCommand"]);
+            run(config, "config")
+                .ok()
+                .and_then(|output| String::from_utf8(output).ok())
+                .map(|ssh| ssh.trim().to_string())
+                .filter(|ssh| !ssh.is_empty())
+        })
+        .or_else(|| {
+            std::env::var("GIT_SSH")
+                .ok()
+                .filter(|ssh| !ssh.is_empty())
+                .map(|ssh| shell_quote(&ssh))
+        })
+        .unwrap_or_else(|| "ssh".to_string());
+    let identity = match auth::ssh_key(auth)? {
+        Some(key) => format!(
+            " -i {} -o IdentitiesOnly=yes",
+            shell_quote(&key.display().to_string())
+        ),
+        None => String::new(),
+    };
+    git.env(
+        "GIT_SSH_COMMAND",
+        format!("{ssh}{identity} -o BatchMode=yes"),
+    );
+
+    if let Some(token) = auth::token(auth)? {
+        // The token stays in the environment, out of the arguments other users can list
+        git.env("GENCORE_GIT_USERNAME", auth::username(auth, None))
+            .env("GENCORE_GIT_TOKEN", token)
+            .args(["-c", "credential.helper=", "-c"])
+            .arg(concat!(
+                "credential.helper=!f() { ",
+                "echo \"username=$GENCORE_GIT_USERNAME\"; ",
+                "echo \"password=$GENCORE_GIT_TOKEN\"; }; f"
+            ));
+    } else if let Some(helper) = &auth.credential_helper {
+        git.arg("-c").arg(format!("credential.helper={helper}"));
+    }
+
+    Ok(())
+}
+
+impl Command {
+    pub fn new(repo: &str, auth: Auth) -> Self {
+        Self {
+            exe: "git".to_string(),
+            repo: PathBuf::from(repo),
+            auth,
+        }
+    }
+
+    pub fn clone(url: &str, path: &Path, auth: &Auth) -> Result<(), GitError> {
+        let mut git = std::process::Command::new("git");
+        git.env("GIT_TERMINAL_PROMPT", "0");
+        authenticate(&mut git, auth, Path::new("."))?;
+        git.args(["clone", "--quiet", "--depth", "1", url])
+            .arg(path);
+        run(git, "clone")?;
+        Ok(())
+    }
+
+    fn git(&self) -> std::process::Command {
+        let mut git = std::process::Command::new(&self.exe);
+        git.arg("--no-pager")
+            .current_dir(&self.repo)
+            .env("GIT_TERMINAL_PROMPT", "0");
+        git
+    }
+
+    /// `git` for the commands reaching the remote, with the credentials of the settings
+    fn remote_git(&self) -> Result<std::process::Command, GitError> {
+        let mut git = self.git();
+        authenticate(&mut git, &self.auth, &self.repo)?;
+        Ok(git)
+    }
+
+    fn is_shallow(&self) -> Result<bool, GitError> {
+        let mut git = self.git();
+        git.args(["rev-parse", "--is-shallow-repository"]);
+        Ok(String::from_utf8(run(git, "rev-parse")?)?.trim() == "true")
+    }
+
+    /// Entries of `ls-tree` run with `args`
+    fn entries(&self, args: &[&str]) -> Result<Vec<TreeEntry>, GitError> {
+        let mut git = self.git();
+        git.args(["ls-tree", "-z"]).args(args);
+        let output = String::from_utf8(run(git, "ls-tree")?)?;
+        let entries = output
+            .split('\0')
+            .filter_map(|line| {
+                let (info, path) = line.split_once('\t')?;
+                let mode = info.split(' ').next()?;
+                Some(TreeEntry {
+                    kind: EntryKind::from_mode(i32::from_str_radix(mode, 8).ok()?)?,
+                    path: PathBuf::from(path),
+                })
+            })
+            .collect();
+
+        Ok(entries)
+    }
+}
+
+/// A commit the remote refused to send as [`GitError::Unreachable`], apart from the other errors
+fn missing_commit(error: GitError, commit: &str) -> GitError {
+    match error {
+        GitError::Command { stderr, .. } if is_missing_commit(&stderr) => {
+            GitError::Unreachable(commit.to_string())
+        }
+        error => error,
+    }
+}
+
+/// Standard output of `git`, an error with its standard error when it fails
+fn run(mut git: std::process::Command, command: &str) -> Result<Vec<u8>, GitError> {
+    let output = git.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if auth::is_failure(&stderr) {
+            return Err(GitError::Auth(stderr));
+        }
+        return Err(GitError::Command {
+            command: command.to_string(),
+            stderr,
+        });
+    }
+
+    Ok(output.stdout)
+}
+
+impl Backend for Command {
+    fn rev_parse(&self, rev: &str) -> Result<String, GitError> {
+        let mut git = self.git();
+        git.args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{rev}^{{commit}}"),
+        ]);
+        let output =
+            run(git, "rev-parse").map_err(|_| GitError::UnknownRevision(rev.to_string()))?;
+
+        Ok(String::from_utf8(output)?.trim().to_string())
+    }
+
+    fn fetch(&self, pin: &Pin) -> Result<String, GitError> {
+        let mut git = self.remote_git()?;
+        git.args(["fetch", "--quiet", REMOTE]);
+        let target = match pin {
+            Pin::Default if !self.is_detached()? => {
+                self.rev_parse("@{upstream}").map_err(|_| {
+                    let mut git = self.git();
+                    git.args(["symbolic-ref", "--quiet", "HEAD"]);
+                    let branch = run(git, "symbolic-ref")
+                        .ok()
+                        .and_then(|output| String::from_utf8(output).ok())
+                        .map_or_else(|| "HEAD".to_string(), |branch| branch.trim().to_string());
+                    GitError::NoUpstream(branch)
+                })?;
+                "@{upstream}".to_string()
+            }
+            Pin::Default => format!("refs/remotes/{REMOTE}/HEAD"),
+            Pin::Branch(branch) => {
+                let target = format!("refs/remotes/{REMOTE}/{branch}");
+                git.arg(format!("+refs/heads/{branch}:{target}"));
+                target
+            }
+            Pin::Tag(tag) => {
+                let target = format!("refs/tags/{tag}");
+                git.arg(format!("+{target}:{target}"));
+                target
+            }
+            Pin::Rev(rev) => {
+                if self.rev_parse(rev).is_err() {
+                    // Servers may refuse a commit id, which is then found on a branch or tag
+                    let mut by_id = self.remote_git()?;
+                    by_id.args(["fetch", "--quiet", REMOTE, rev]);
+                    match run(by_id, "fetch").map_err(|error| missing_commit(error, rev)) {
+                        Ok(_) | Err(GitError::Unreachable(_)) => {}
+                        Err(error) => return Err(error),
+                    }
+                }
+                if self.rev_parse(rev).is_err() {
+                    git.args([
+                        &format!("+refs/heads/*:refs/remotes/{REMOTE}/*"),
+                        "+refs/tags/*:refs/tags/*",
+                    ]);
+                    run(git, "fetch")?;
+                }
+                // The tips only bring the last commits into a shallow clone, an older rev needs the history
+                if self.rev_parse(rev).is_err() && self.is_shallow()? {
+                    let mut unshallow = self.remote_git()?;
+                    unshallow.args(["fetch", "--quiet", "--unshallow", REMOTE]);
+                    run(unshallow, "fetch")?;
+                }
+                return self.rev_parse(rev);
+            }
+        };
+        run(git, "fetch")?;
+
+        self.rev_parse(&target)
+    }
+
+    fn fetch_commit(&self, commit: &str) -> Result<(), GitError> {
+        let mut git = self.remote_git()?;
+        git.args(["fetch", "--quiet"]);
+        if self.is_shallow()? {
+            git.args(["--depth", "1"]);
+        }
+        git.args([REMOTE, commit]);
+        run(git, "fetch").map_err(|error| missing_commit(error, commit))?;
+        Ok(())
+    }
+
+    fn set_ref(&self, name: &str, commit: &str) -> Result<(), GitError> {
+        let mut git = self.git();
+        git.args(["update-ref", name, commit]);
+        run(git, "update-ref")?;
+        Ok(())
+    }
+
+    fn local_changes(&self) -> Result<Vec<PathBuf>, GitError> {
+        let mut git = self.git();
+        git.args([
+            "status",
+            "--porcelain=v1",
+            "-z",
+            "--no-renames",
+            "--untracked-files=all",
+        ]);
+        let output = String::from_utf8(run(git, "status")?)?;
+
+        Ok(output
+            .split('\0')
+            .filter_map(|entry| entry.get(3..))
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    fn fast_forward(&self, commit: &str) -> Result<bool, GitError> {
+        if self.rev_parse("HEAD")? == commit {
+            return Ok(false);
+        }
+
+        let mut git = self.git();
+        git.args(["merge-base", "--is-ancestor", "HEAD", commit]);
+        if run(git, "merge-base").is_err() {
+            return Ok(false);
+        }
+
+        let mut git = self.git();
+        git.args(["merge", "--quiet", "--ff-only", commit]);
+        run(git, "merge")?;
+        Ok(true)
+    }
+
+    fn checkout(&self, commit: &str) -> Result<(), GitError> {
+        let mut git = self.git();
+        git.args(["checkout", "--quiet", "--detach", commit]);
+        run(git, "checkout")?;
+        Ok(())
+    }
+
+    fn is_detached(&self) -> Result<bool, GitError> {
+        let mut git = self.git();
+        git.args(["symbolic-ref", "--quiet", "HEAD"]);
+        Ok(run(git, "symbolic-ref").is_err())
+    }
+
+    fn diff(&self, from_commit: &str, to_commit: &str) -> Result<Vec<Change>, GitError> {
+        let mut git = self.git();
+        git.args([
+            "diff",
+            "--name-status",
+            "--no-renames",
+            "-z",
+            from_commit,
+            to_commit,
+        ]);
+        let output = String::from_utf8(run(git, "diff")?)?;
+
+        let mut fields = output.split('\0');
+        let mut changes = Vec::new();
+        while let (Some(status), Some(path)) = (fields.next(), fields.next()) {
+            let path = Some(PathBuf::from(path));
+            changes.push(match status {
+                "A" => Change {
+                    old_path: None,
+                    new_path: path,
+                },
+                "D" => Change {
+                    old_path: path,
+                    new_path: None,
+                },
+                _ => Change {
+                    old_path: path.clone(),
+                    new_path: path,
+                },
+            });
+        }
+
+        Ok(changes)
+    }
+
+    fn ls_tree(&self, commit: &str, dir: &Path) -> Result<Vec<TreeEntry>, GitError> {
+        if dir.as_os_str().is_empty() {
+            return self.entries(&[commit]);
+        }
+
+        self.entries(&[commit, "--", &format!("{}/", dir.display())])
+    }
+
+    fn ls_dirs(&self, commit: &str) -> Result<Vec<String>, GitError> {
+        let mut git = self.git();
+        git.args(["ls-tree", "-r", "-d", "-z", "--name-only", commit]);
+        let output = String::from_utf8(run(git, "ls-tree")?)?;
+
+        Ok(output
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn object_kind(&self, commit: &str, path: &Path) -> Result<EntryKind, GitError> {
+        if path.as_os_str().is_empty() {
+            return Ok(EntryKind::Dir);
+        }
+
+        let not_found = || GitError::NotFound {
+            commit: commit.to_string(),
+            path: path.to_path_buf(),
+        };
+        self.entries(&[commit, "--", &path.display().to_string()])?
+            .into_iter()
+            .find(|entry| entry.path == path)
+            .map(|entry| entry.kind)
+            .ok_or_else(not_found)
+    }
+
+    fn cat_file(&self, commit: &str, path: &Path) -> Result<Vec<u8>, GitError> {
+        let mut git = self.git();
+        git.args(["cat-file", "blob", &format!("{commit}:{}", path.display())]);
+
+        run(git, "cat-file").map_err(|_| GitError::NotFound {
+            commit: commit.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{Auth, GitBackend};
+
+pub mod auth;
+mod builtin;
+mod command;
+
+#[derive(Debug, thiserror::Error)]
+pub enum GitError {
+    #[error("cannot run git: {0}")]
+    Spawn(#[from] std::io::Error),
+    #[error("git {command} failed: {stderr}")]
+    Command { command: String, stderr: String },
+    #[error("git output is not valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    Libgit2(#[from] git2::Error),
+    #[error("unknown revision {0}")]
+    UnknownRevision(String),
+    #[error("no {} at {commit}", path.display())]
+    NotFound { commit: String, path: PathBuf },
+    #[error("{0} does not track a remote branch")]
+    NoUpstream(String),
+    #[error("the remote refused the credentials: {0}")]
+    Auth(String),
+    #[error("the token variable {0} is not set")]
+    TokenNotSet(String),
+    #[error("no SSH key at {}", .0.display())]
+    KeyNotFound(PathBuf),
+    #[error("commit {0} is neither in the clone nor on the remote, the decks need a reconcile")]
+    Unreachable(String),
+}
+
+pyo3::create_exception!(
+    gencore,
+    UnreachableCommit,
+    pyo3::exceptions::PyRuntimeError,
+    "The commit the decks were synced from is gone, only a reconcile can bring them up to date"
+);
+
+pyo3::create_exception!(
+    gencore,
+    AuthError,
+    pyo3::exceptions::PyRuntimeError,
+    "The credentials of a repository are missing or refused"
+);
+
+/// Raised in Python as a `RuntimeError`, like the other errors of gencore
+impl From<GitError> for pyo3::PyErr {
+    fn from(error: GitError) -> Self {
+        match error {
+            GitError::Unreachable(_) => UnreachableCommit::new_err(error.to_string()),
+            GitError::Auth(_) | GitError::TokenNotSet(_) | GitError::KeyNotFound(_) => {
+                AuthError::new_err(error.to_string())
+            }
+            _ => pyo3::exceptions::PyRuntimeError::new_err(error.to_string()),
+        }
+    }
+}
+
+/// What the clone of a repository follows
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Pin {
+    /// The branch tracked by the checkout, else the default branch of the remote
+    #[default]
+    Default,
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+/// Remote the clones are made from
+const REMOTE: &str = "origin";
+
+/// What remotes answer when asked for a commit they do not have, or do not serve by id
+const MISSING_COMMIT: [&str; 4] = [
+    "not our ref",
+    "unadvertised object",
+    "couldn't find remote ref",
+    "no such remote ref",
+];
+
+/// Whether the remote refused to send a commit, rather than failing to connect or authenticate
+fn is_missing_commit(message: &str) -> bool {
+    MISSING_COMMIT
+        .iter()
+        .any(|missing| message.contains(missing))
+}
+
+/// Ref moved to every fetched commit, so the checkout of the user is never merged into
+pub const SYNC_REF: &str = "refs/gencore/synced";
+
+pub struct GitUpdate {
+    pub from_commit: String,
+    pub to_commit: String,
+    /// Files of the checkout that differ from its HEAD, they are not synced
+    pub local_changes: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    /// Not followed, its target could be outside of the repository
+    Symlink,
+    Dir,
+    Submodule,
+}
+
+impl EntryKind {
+    fn from_mode(mode: i32) -> Option<Self> {
+        match mode {
+            0o100644 | 0o100755 => Some(Self::File),
+            0o120000 => Some(Self::Symlink),
+            0o040000 => Some(Self::Dir),
+            0o160000 => Some(Self::Submodule),
+            _ => None,
+        }
+    }
+}
+
+pub struct TreeEntry {
+    pub kind: EntryKind,
+    pub path: PathBuf,
+}
+
+impl TreeEntry {
+    /// Regular file, symlinks are not followed
+    pub fn is_blob(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+}
+
+/// File changed between two commits, a path is `None` when the file was added or deleted
+#[derive(Debug, PartialEq, Eq)]
+pub struct Change {
+    pub old_path: Option<PathBuf>,
+    pub new_path: Option<PathBuf>,
+}
+
+/// Operations on a repository, done in process or by the `git` command
+pub trait Backend {
+    /// Commit `rev` points to
+    fn rev_parse(&self, rev: &str) -> Result<String, GitError>;
+    /// Fetch the commit `pin` points to on the remote, without touching the checkout
+    fn fetch(&self, pin: &Pin) -> Result<String, GitError>;
+    /// Fetch `commit` alone by its id, with a depth of 1 in a shallow clone
+    fn fetch_commit(&self, commit: &str) -> Result<(), GitError>;
+    /// Point `name` to `commit`, whatever it pointed to
+    fn set_ref(&self, name: &str, commit: &str) -> Result<(), GitError>;
+    /// Modified, deleted and untracked files of the checkout
+    fn local_changes(&self) -> Result<Vec<PathBuf>, GitError>;
+    /// Move the current branch and the checkout to `commit` when it is a descendant of HEAD
+    fn fast_forward(&self, commit: &str) -> Result<bool, GitError>;
+    /// Check `commit` out on a detached HEAD, keeping local edits
+    fn checkout(&self, commit: &str) -> Result<(), GitError>;
+    fn is_detached(&self) -> Result<bool, GitError>;
+    /// Files changed from `from_commit` to `to_commit`, without rename detection
+    fn diff(&self, from_commit: &str, to_commit: &str) -> Result<Vec<Change>, GitError>;
+    /// Entries directly inside `dir`, with their path from the root
+    fn ls_tree(&self, commit: &str, dir: &Path) -> Result<Vec<TreeEntry>, GitError>;
+    /// Every folder of the tree of `commit`
+    fn ls_dirs(&self, commit: &str) -> Result<Vec<String>, GitError>;
+    fn object_kind(&self, commit: &str, path: &Path) -> Result<EntryKind, GitError>;
+    fn cat_file(&self, commit: &str, path: &Path) -> Result<Vec<u8>, GitError>;
+}
+
+/// A repository on disk, with the backend chosen in the settings
+pub struct Git {
+    pub repo: String,
+    backend: Box<dyn Backend>,
+}
+
+impl fmt::Debug for Git {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Git").field("repo", &self.repo).finish()
+    }
+}
+
+impl Git {
+    pub fn open(repo: String, backend: GitBackend) -> Self {
+        Self::open_with_auth(repo, backend, Auth::default())
+    }
+
+    /// Repository whose remote is reached with `auth`
+    pub fn open_with_auth(repo: String, backend: GitBackend, auth: Auth) -> Self {
+        let backend: Box<dyn Backend> = match backend {
+            GitBackend::Builtin => Box::new(builtin::Builtin::new(&repo, auth)),
+            GitBackend::System => Box::new(command::Command::new(&repo, auth)),
+        };
+
+        Self { repo, backend }
+    }
+
+    /// Shallow clone of `url` into `path`, checked out at `pin`
+    pub fn clone(
+        url: &str,
+        path: &str,
+        backend: GitBackend,
+        auth: &Auth,
+        pin: &Pin,
+    ) -> Result<Self, GitError> {
+        match backend {
+            GitBackend::Builtin => builtin::Builtin::clone(url, Path::new(path), auth)?,
+            GitBackend::System => command::Command::clone(url, Path::new(path), auth)?,
+        }
+
+        let git = Self::open_with_auth(path.to_string(), backend, auth.clone());
+        if *pin == Pin::Default {
+            git.backend.set_ref(SYNC_REF, &git.rev_parse("HEAD")?)?;
+        } else {
+            git.update(pin)?;
+        }
+        Ok(git)
+    }
+
+    pub fn rev_parse(&self, rev: &str) -> Result<String, GitError> {
+        self.backend.rev_parse(rev)
+    }
+
+    /// Fetch `pin` into [`SYNC_REF`], `from_commit` and `to_commit` are equal when nothing changed
+    ///
+    /// A force-pushed upstream or a new pin only moves the ref. Without local changes the
+    /// checkout follows: fast-forwarded on its branch, or detached at a pinned commit.
+    pub fn update(&self, pin: &Pin) -> Result<GitUpdate, GitError> {
+        let from_commit = self
+            .rev_parse(SYNC_REF)
+            .or_else(|_| self.rev_parse("HEAD"))?;
+        let to_commit = self.backend.fetch(pin)?;
+        self.backend.set_ref(SYNC_REF, &to_commit)?;
+
+        let local_changes = self.backend.local_changes()?;
+        if local_changes.is_empty() {
+            if *pin == Pin::Default && !self.backend.is_detached()? {
+                self.backend.fast_forward(&to_commit)?;
+            } else if self.rev_parse("HEAD")? != to_commit {
+                self.backend.checkout(&to_commit)?;
+            }
+        }
+
+        Ok(GitUpdate {
+            from_commit,
+            to_commit,
+            local_changes,
+        })
+    }
+
+    /// Make sure `commit` can be read, fetching it when a shallow boundary or a gc left it out
+    pub fn ensure_commit(&self, commit: &str) -> Result<(), GitError> {
+        if self.rev_parse(commit).is_ok() {
+            return Ok(());
+        }
+
+        // The remote refuses commits it no longer has, or ids it does not advertise
+        match self.backend.fetch_commit(commit) {
+            Ok(()) | Err(GitError::Unreachable(_)) => {}
+            Err(error) => return Err(error),
+        }
+        self.rev_parse(commit)
+            .map(|_| ())
+            .map_err(|_| GitError::Unreachable(commit.to_string()))
+    }
+
+    pub fn diff(&self, from_commit: &str, to_commit: &str) -> Result<Vec<Change>, GitError> {
+        self.backend.diff(from_commit, to_commit)
+    }
+
+    pub fn ls_tree(&self, commit: &str, dir: &Path) -> Result<Vec<TreeEntry>, GitError> {
+        self.backend.ls_tree(commit, dir)
+    }
+
+    /// Every folder of the tree of `commit`, relative to the repository
+    pub fn ls_dirs(&self, commit: &str) -> Result<Vec<String>, GitError> {
+        self.backend.ls_dirs(commit)
+    }
+
+    pub fn object_kind(&self, commit: &str, path: &Path) -> Result<EntryKind, GitError> {
+        self.backend.object_kind(commit, path)
+    }
+
+    pub fn cat_file(&self, commit: &str, path: &Path) -> Result<Vec<u8>, GitError> {
+        self.backend.cat_file(commit, path)
+    }
+}
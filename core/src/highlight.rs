@@ -0,0 +1,171 @@
+use std::{collections::BTreeSet, fmt::Write, sync::OnceLock};
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Color, Theme, ThemeSet},
+    html::{IncludeBackground, styled_line_to_highlighted_html},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+/// Theme of the code blocks when the settings do not name one
+pub const DEFAULT_THEME: &str = "InspiredGitHub";
+
+/// Background of the highlighted lines, when the theme has none
+const LINE_HIGHLIGHT: Color = Color {
+    r: 0xff,
+    g: 0xe0,
+    b: 0x66,
+    a: 0x60,
+};
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Theme named `name`, `None` when syntect has none of that name
+pub fn theme(name: &str) -> Option<&'static Theme> {
+    themes().themes.get(name)
+}
+
+/// Names of the themes bundled with syntect
+pub fn theme_names() -> Vec<&'static str> {
+    themes().themes.keys().map(String::as_str).collect()
+}
+
+/// What the info string of a fence asks for, as in `rust numbered {3,5-7}`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CodeInfo {
+    pub language: String,
+    /// Lines shown with a background, counted from 1
+    pub lines: BTreeSet<usize>,
+    pub numbered: bool,
+    /// Ranges asked for that are not lines of the block, only the lines it has are kept
+    pub invalid: Vec<String>,
+}
+
+impl CodeInfo {
+    /// What `info` asks for a block of `line_count` lines
+    pub fn parse(info: &str, line_count: usize) -> Self {
+        let mut code_info = Self::default();
+        let (words, ranges) = match info.split_once('{') {
+            Some((words, ranges)) => (words, ranges.split('}').next().unwrap_or_default()),
+            None => (info, ""),
+        };
+
+        for word in words.split_whitespace() {
+            match word {
+                "numbered" => code_info.numbered = true,
+                language if code_info.language.is_empty() => {
+                    code_info.language = language.to_string()
+                }
+                _ => {}
+            }
+        }
+
+        for range in ranges.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (Ok(start), Ok(end)) = (start.trim().parse::<usize>(), end.trim().parse()) else {
+                code_info.invalid.push(range.to_string());
+                continue;
+            };
+            if start == 0 || start > end || end > line_count {
+                code_info.invalid.push(range.to_string());
+            }
+            // Bounded by the block, a shared repository may ask for any number of lines
+            code_info.lines.extend(start.max(1)..=end.min(line_count));
+        }
+
+        code_info
+    }
+
+    fn syntax(&self) -> Option<&'static SyntaxReference> {
+        if self.language.is_empty() {
+            return None;
+        }
+
+        syntaxes().find_syntax_by_token(&self.language)
+    }
+}
+
+fn css_color(color: Color) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}{:02x}",
+        color.r, color.g, color.b, color.a
+    )
+}
+
+/// `code` as HTML with inline styles, `None` when its language is unknown and no line is asked for
+pub fn highlight(code: &str, info: &CodeInfo, theme: &Theme) -> Option<String> {
+    let syntax = match info.syntax() {
+        Some(syntax) => syntax,
+        None if info.numbered || !info.lines.is_empty() => syntaxes().find_syntax_plain_text(),
+        None => return None,
+    };
+
+    let background = theme.settings.background.unwrap_or(Color::WHITE);
+    let foreground = theme.settings.foreground.unwrap_or(Color::BLACK);
+    let line_highlight = theme.settings.line_highlight.unwrap_or(LINE_HIGHLIGHT);
+    let gutter = theme.settings.gutter_foreground.unwrap_or(foreground);
+    let width = code.lines().count().to_string().len();
+
+    let mut output = String::new();
+    write!(
+        output,
+        "<pre style=\"background-color:{};color:{}\">",
+        css_color(background),
+        css_color(foreground)
+    )
+    .ok()?;
+    if info.language.is_empty() {
+        output.push_str("<code>");
+    } else {
+        output.push_str("<code class=\"language-");
+        comrak::html::escape(&mut output, &info.language).ok()?;
+        output.push_str("\">");
+    }
+
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    for (index, line) in LinesWithEndings::from(code).enumerate() {
+        let number = index + 1;
+        let regions = highlighter.highlight_line(line, syntaxes()).ok()?;
+        let html = styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()?;
+        // The line ending is inside the last span, each line is closed below instead
+        let html = html.replace(['\r', '\n'], "");
+
+        if info.lines.contains(&number) {
+            write!(
+                output,
+                "<span style=\"display:block;background-color:{}\">",
+                css_color(line_highlight)
+            )
+            .ok()?;
+        } else {
+            output.push_str("<span>");
+        }
+        if info.numbered {
+            write!(
+                output,
+                "<span style=\"user-select:none;color:{}\">{number:>width$} </span>",
+                css_color(gutter)
+            )
+            .ok()?;
+        }
+        output.push_str(&html);
+        // A block span already ends the line
+        if info.lines.contains(&number) {
+            output.push_str("</span>");
+        } else {
+            output.push_str("</span>\n");
+        }
+    }
+    output.push_str("</code></pre>\n");
+
+    Some(output)
+}
@@ -1,26 +1,52 @@
 use std::{
     collections::{HashMap, HashSet},
     path::{Path, PathBuf},
-    process::Stdio,
 };
 
 use crate::{
+    config::Settings,
     data::{DeckOutput, Output},
     generator::Generator,
+    git::{Git, GitError, Pin},
+    source::{GitTree, Source, WorkTree},
 };
 
 pub struct Init<'a> {
     url: &'a str,
     output_path: &'a str,
     target_path: &'a Path,
+    settings: &'a Settings,
+    pin: Pin,
 }
 
 impl<'a> Init<'a> {
-    pub const fn new(url: &'a str, output_path: &'a str, target_path: &'a Path) -> Self {
+    pub const fn new(
+        url: &'a str,
+        output_path: &'a str,
+        target_path: &'a Path,
+        settings: &'a Settings,
+    ) -> Self {
         Self {
             url,
             output_path,
             target_path,
+            settings,
+            pin: Pin::Default,
+        }
+    }
+
+    /// Clone at `pin` instead of the default branch
+    pub fn with_pin(mut self, pin: Pin) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    /// Where the repository is cloned, the target itself for a local folder
+    fn repository_path(&self) -> &Path {
+        if self.output_path.is_empty() {
+            self.target_path
+        } else {
+            Path::new(self.output_path)
         }
     }
 
@@ -61,38 +87,60 @@ impl<'a> Init<'a> {
             .collect())
     }
 
-    pub fn git_clone(&self) -> anyhow::Result<()> {
-        let Self {
-            url, output_path, ..
-        } = self;
+    pub fn git_clone(&self) -> Result<Git, GitError> {
+        Git::clone(
+            self.url,
+            self.output_path,
+            self.settings.git,
+            &self.settings.auth,
+            &self.pin,
+        )
+    }
 
-        let git = std::process::Command::new("git")
-            .args(["clone", "--depth", "1", url, output_path])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
-        eprintln!("{}", String::from_utf8(git.stdout)?);
-        eprintln!("{}", String::from_utf8(git.stderr)?);
+    pub fn generate(&self) -> anyhow::Result<Output> {
+        Ok(self.generate_decks(&WorkTree, self.get_subdecks_path()?))
+    }
 
-        Ok(())
+    /// Decks as they are at `commit`, whatever the checkout holds
+    pub fn generate_at(&self, git: &Git, commit: &str) -> anyhow::Result<Output> {
+        let target = self
+            .target_path
+            .strip_prefix(self.repository_path())
+            .unwrap_or(Path::new(""));
+        let subdecks = git
+            .ls_dirs(commit)?
+            .into_iter()
+            .filter_map(|dir| Some(Path::new(&dir).strip_prefix(target).ok()?.to_path_buf()))
+            .filter(|dir| {
+                !dir.as_os_str().is_empty()
+                    && !dir
+                        .components()
+                        .any(|part| part.as_os_str().to_string_lossy().starts_with('.'))
+            })
+            .collect();
+
+        Ok(self.generate_decks(&GitTree::new(git, commit), subdecks))
     }
 
-    pub fn generate(&self) -> anyhow::Result<Output> {
+    fn generate_decks(&self, source: &dyn Source, subdecks: Vec<PathBuf>) -> Output {
         let mut decks: Output = HashMap::new();
-        for path in self.get_subdecks_path()? {
+        for path in subdecks {
             let name = path.to_str().unwrap().replace('/', "::");
+            let subproject_path = self.target_path.join(path.as_path());
+            let generator = Generator::new(source, self.settings, &subproject_path)
+                .with_root(self.repository_path());
+            let added = generator.generate_card_from_folder(&name);
             decks.insert(
                 name,
                 DeckOutput {
-                    added: Generator {
-                        subproject_path: self.target_path.join(path.as_path()).as_path(),
-                    }
-                    .generate_card_from_folder(),
+                    added,
+                    media: generator.take_media(),
+                    diagnostics: generator.take_diagnostics(),
                     ..Default::default()
                 },
             );
         }
 
-        Ok(decks)
+        decks
     }
 }
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::data::{DeckOutput, Output, UpdatedCard};
+
+/// Hashes of the cards Anki currently holds by note guid, by deck name
+///
+/// Notes added before the guids were derived from the ids hold a random one, they are only
+/// matched by hash.
+pub type Inventory = HashMap<String, HashMap<String, String>>;
+
+fn is_in_root(deck: &str, root_deck: &str) -> bool {
+    deck == root_deck || deck.starts_with(&format!("{root_deck}::"))
+}
+
+/// Changes needed for the decks under `root_deck` in `inventory` to match `decks`
+pub fn reconcile(decks: Output, inventory: &Inventory, root_deck: &str) -> Output {
+    let mut output = Output::new();
+    for (name, deck) in decks {
+        let empty = HashMap::new();
+        let held = inventory.get(&name).unwrap_or(&empty);
+        let held_hashes: HashSet<&String> = held.values().collect();
+        let guids: HashSet<&String> = deck.added.iter().map(|f| &f.guid).collect();
+        let hashes: HashSet<&String> = deck.added.iter().map(|f| &f.hash).collect();
+
+        let deleted = held
+            .iter()
+            .filter(|(guid, hash)| !guids.contains(guid) && !hashes.contains(hash))
+            .map(|(_, hash)| hash.clone())
+            .collect();
+
+        let mut added = Vec::new();
+        let mut updated = Vec::new();
+        for card in &deck.added {
+            match held.get(&card.guid) {
+                Some(hash) if *hash == card.hash => {}
+                // Edited since Anki got it, the note keeps its history
+                Some(hash) => updated.push(UpdatedCard {
+                    old_hash: hash.clone(),
+                    card: card.clone(),
+                }),
+                None if held_hashes.contains(&card.hash) => {}
+                None => added.push(card.clone()),
+            }
+        }
+
+        let mut reconciled = DeckOutput {
+            added,
+            updated,
+            deleted,
+            diagnostics: deck.diagnostics,
+            ..Default::default()
+        };
+        reconciled.collect_media(&deck.media);
+        output.insert(name, reconciled);
+    }
+
+    for (name, held) in inventory {
+        if output.contains_key(name) || !is_in_root(name, root_deck) {
+            continue;
+        }
+
+        output.insert(
+            name.clone(),
+            DeckOutput {
+                deleted: held.values().cloned().collect(),
+                ..Default::default()
+            },
+        );
+    }
+
+    output
+}
@@ -1,60 +1,266 @@
 use pyo3::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::{config::Config, data::Output, init::Init, updater::Updater};
+use crate::{
+    apkg::Package,
+    config::{Config, KATEX_SCRIPTS, KATEX_STYLESHEET, Repo, Settings},
+    data::{Card, Output},
+    git::{AuthError, Git, GitError, Pin, SYNC_REF, UnreachableCommit},
+    init::Init,
+    inventory::{Inventory, reconcile as reconcile_decks},
+    state::SyncState,
+    updater::Updater,
+};
 
+mod apkg;
 mod config;
 mod data;
+mod diagram;
+mod frontmatter;
 mod generator;
 mod git;
+mod highlight;
 mod init;
+mod inventory;
+mod macros;
 mod markdown;
+mod source;
+mod state;
+mod svg;
 mod updater;
 
 #[cfg(test)]
 mod tests;
 
-pub fn init(url: &str, output_path: &str, target_path: &Path) -> PyResult<Output> {
-    let init = Init::new(url, output_path, target_path);
+pub fn init(
+    url: &str,
+    output_path: &str,
+    target_path: &Path,
+    settings: &Settings,
+    pin: Pin,
+) -> PyResult<Output> {
+    let init = Init::new(url, output_path, target_path, settings).with_pin(pin);
     init.git_clone()?;
     Ok(init.generate()?)
 }
 
-#[pyfunction]
-pub fn update(path: String) -> PyResult<Output> {
-    Ok(Updater::new(path).generate()?)
+/// Nest every deck under `root_deck_name`
+fn with_root_deck(decks: Output, root_deck_name: &str) -> Output {
+    decks
+        .into_iter()
+        .map(|(deck, mut cards)| {
+            cards.renamed_from = cards.renamed_from.map(|f| format!("{root_deck_name}::{f}"));
+            (format!("{root_deck_name}::{deck}"), cards)
+        })
+        .collect()
 }
 
-#[pyfunction]
-pub fn from_config(path: String) -> PyResult<Output> {
-    let config = Config::from_file(path)?;
-    let mut output = Output::new();
-    for (name, repo) in &config.repo {
+/// Give the cards their note guid, from their deck and `namespace`
+fn with_guids(mut decks: Output, namespace: &str) -> Output {
+    for (deck, output) in &mut decks {
+        let cards = output
+            .added
+            .iter_mut()
+            .chain(output.updated.iter_mut().map(|f| &mut f.card));
+        for card in cards {
+            card.guid = Card::guid_for(namespace, deck, &card.id);
+        }
+    }
+    decks
+}
+
+pub fn update(
+    path: String,
+    target_path: &Path,
+    settings: Settings,
+    pin: Pin,
+    state: &mut SyncState,
+) -> PyResult<Output> {
+    Updater::new(path, settings)
+        .with_target(target_path)
+        .with_pin(pin)
+        .generate(state)
+        .map_err(|error| match error.downcast::<GitError>() {
+            Ok(error) => error.into(),
+            Err(error) => error.into(),
+        })
+}
+
+/// One repository of the config, as [`from_config`] and [`reconcile`] sync it
+struct RepoSync {
+    slug: String,
+    url: String,
+    root_deck_name: String,
+    target_path: PathBuf,
+    settings: Settings,
+    pin: Pin,
+}
+
+impl RepoSync {
+    fn new(name: &str, repo: &Repo) -> anyhow::Result<Self> {
         let slug = repo.get_slug();
-        let url = repo.get_url();
-        let root_deck_name = repo.get_custom_deck_name().unwrap_or_else(|| name.clone());
-        let subfolder = repo.get_subfolder();
-        let repo_folder = std::path::Path::new(&slug);
-
-        if repo_folder.exists() {
-            let values = update(repo_folder.to_str().unwrap().to_string())?;
-            for (decks, cards) in values {
-                output.insert(format!("{root_deck_name}::{decks}"), cards);
-            }
+        Ok(Self {
+            target_path: Path::new(&slug).join(repo.get_subfolder()),
+            url: repo.get_url().clone(),
+            root_deck_name: repo
+                .get_custom_deck_name()
+                .unwrap_or_else(|| name.to_string()),
+            settings: repo.get_settings(),
+            pin: repo.get_pin()?,
+            slug,
+        })
+    }
+
+    fn init(&self) -> Init<'_> {
+        Init::new(&self.url, &self.slug, &self.target_path, &self.settings)
+            .with_pin(self.pin.clone())
+    }
+
+    /// Checkout at its pin and the commit it points to, cloning the repository if needed
+    fn checkout(&self) -> PyResult<(Git, String)> {
+        if Path::new(&self.slug).exists() {
+            let git = Git::open_with_auth(
+                self.slug.clone(),
+                self.settings.git,
+                self.settings.auth.clone(),
+            );
+            let commit = git.update(&self.pin)?.to_commit;
+            Ok((git, commit))
         } else {
-            let values = init(url, &slug, &repo_folder.join(subfolder))?;
-            for (decks, cards) in values {
-                output.insert(format!("{root_deck_name}::{decks}"), cards);
-            }
+            let git = self.init().git_clone()?;
+            let commit = git.rev_parse(SYNC_REF)?;
+            Ok((git, commit))
         }
     }
+}
+
+/// Output of `sync` for every repository of the config at `path`
+///
+/// The states are kept pending until Anki confirms the output with [`commit_state`],
+/// nothing is written when a repository fails.
+fn sync_repos(
+    path: String,
+    mut sync: impl FnMut(&RepoSync, &mut SyncState) -> PyResult<Output>,
+) -> PyResult<Output> {
+    let config = Config::from_file(path)?;
+    let mut output = Output::new();
+    let mut states = Vec::new();
+    for (name, repo) in &config.repo {
+        let repo = RepoSync::new(name, repo)?;
+        let mut state = SyncState::load(&SyncState::path_for(&repo.slug))?;
+        output.extend(sync(&repo, &mut state)?);
+        states.push((repo.slug, state));
+    }
 
+    for (slug, state) in states {
+        state.save(&SyncState::pending_path_for(&slug))?;
+    }
     Ok(output)
 }
 
+#[pyfunction]
+pub fn from_config(path: String) -> PyResult<Output> {
+    sync_repos(path, |repo, state| {
+        let values = if Path::new(&repo.slug).exists() && state.last_commit.is_some() {
+            update(
+                repo.slug.clone(),
+                &repo.target_path,
+                repo.settings.clone(),
+                repo.pin.clone(),
+                state,
+            )?
+        } else if Path::new(&repo.slug).exists() {
+            // Cloned by a sync Anki never confirmed, every card is sent again
+            let (git, commit) = repo.checkout()?;
+            let values = repo.init().generate_at(&git, &commit)?;
+            state.last_commit = Some(commit);
+            values
+        } else {
+            let values = init(
+                &repo.url,
+                &repo.slug,
+                &repo.target_path,
+                &repo.settings,
+                repo.pin.clone(),
+            )?;
+            let git = Git::open(repo.slug.clone(), repo.settings.git);
+            state.last_commit = Some(git.rev_parse(SYNC_REF)?);
+            values
+        };
+
+        Ok(with_root_deck(
+            with_guids(values, &repo.slug),
+            &repo.root_deck_name,
+        ))
+    })
+}
+
+/// Changes needed for Anki, holding `inventory`, to match every repository at HEAD
+#[pyfunction]
+pub fn reconcile(path: String, inventory: Inventory) -> PyResult<Output> {
+    sync_repos(path, |repo, state| {
+        let (git, commit) = repo.checkout()?;
+        let decks = with_root_deck(
+            with_guids(repo.init().generate_at(&git, &commit)?, &repo.slug),
+            &repo.root_deck_name,
+        );
+        state.last_commit = Some(commit);
+        Ok(reconcile_decks(decks, &inventory, &repo.root_deck_name))
+    })
+}
+
+/// Record that Anki holds the output of the last [`from_config`] or [`reconcile`]
+#[pyfunction]
+pub fn commit_state(path: String) -> PyResult<()> {
+    let config = Config::from_file(path)?;
+    for repo in config.repo.values() {
+        SyncState::commit(&repo.get_slug())?;
+    }
+    Ok(())
+}
+
+/// Package the decks of the repository `name` of the config at `path` into `apkg_path`
+///
+/// The cards, their guids and the note types are the ones the add-on syncs.
+#[pyfunction]
+pub fn write_apkg(path: String, name: String, apkg_path: String) -> PyResult<()> {
+    let config = Config::from_file(path)?;
+    let repo = config
+        .repo
+        .get(&name)
+        .ok_or_else(|| anyhow::anyhow!("No repository {name} in the config"))?;
+    let repo = RepoSync::new(&name, repo)?;
+    let (git, commit) = repo.checkout()?;
+    let decks = with_root_deck(
+        with_guids(repo.init().generate_at(&git, &commit)?, &repo.slug),
+        &repo.root_deck_name,
+    );
+    Package::new(&decks)
+        .with_katex(config.katex_assets())
+        .write_to_file(Path::new(&apkg_path))?;
+    Ok(())
+}
+
+/// KaTeX files the note types load from the network: `none`, `stylesheet` or `scripts`
+#[pyfunction]
+pub fn katex_assets(path: String) -> PyResult<&'static str> {
+    Ok(Config::from_file(path)?.katex_assets().as_str())
+}
+
 #[pymodule]
 #[pyo3(name = "gencore")]
 fn gencore(module: &Bound<'_, PyModule>) -> PyResult<()> {
     module.add_function(wrap_pyfunction!(from_config, module)?)?;
+    module.add_function(wrap_pyfunction!(reconcile, module)?)?;
+    module.add_function(wrap_pyfunction!(commit_state, module)?)?;
+    module.add_function(wrap_pyfunction!(write_apkg, module)?)?;
+    module.add_function(wrap_pyfunction!(katex_assets, module)?)?;
+    module.add("KATEX_STYLESHEET", KATEX_STYLESHEET)?;
+    module.add("KATEX_SCRIPTS", KATEX_SCRIPTS)?;
+    module.add(
+        "UnreachableCommit",
+        module.py().get_type::<UnreachableCommit>(),
+    )?;
+    module.add("AuthError", module.py().get_type::<AuthError>())?;
     Ok(())
 }
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::anyhow;
+
+/// Macros expanding into macros are followed this deep at most, recursive ones would never end
+const MAX_DEPTH: usize = 32;
+
+/// Macros expanded in one math block at most, one using another twice doubles at each level
+const MAX_EXPANSIONS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub arguments: usize,
+    /// Value of the first argument when it is optional
+    pub default: Option<String>,
+    pub body: String,
+}
+
+/// LaTeX macros of a repository, expanded in the math of the cards
+#[derive(Debug, Default)]
+pub struct Macros(BTreeMap<String, Macro>);
+
+/// Name of the control sequence starting `input`, without its backslash
+fn control_sequence(input: &str) -> Option<&str> {
+    let rest = input.strip_prefix('\\')?;
+    let letters = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    if letters > 0 {
+        return Some(&rest[..letters]);
+    }
+
+    rest.chars().next().map(|c| &rest[..c.len_utf8()])
+}
+
+/// Content of the group opened by `open` at the start of `input`, and what follows it
+fn group(input: &str, open: char, close: char) -> Option<(&str, &str)> {
+    let rest = input.strip_prefix(open)?;
+    let mut depth = 1;
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&rest[..i], &rest[i + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// One argument: a braced group or a single token
+fn argument(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    if input.starts_with('{') {
+        return group(input, '{', '}');
+    }
+
+    let len = match control_sequence(input) {
+        Some(name) => name.len() + 1,
+        None => input.chars().next()?.len_utf8(),
+    };
+    Some((&input[..len], &input[len..]))
+}
+
+/// Name of the macro defined by `{\name}` or `\name`
+fn defined_name(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    let (name, rest) = match group(input, '{', '}') {
+        Some((name, rest)) => (name.trim(), rest),
+        None => {
+            let name = control_sequence(input)?;
+            (&input[..=name.len()], &input[name.len() + 1..])
+        }
+    };
+
+    Some((name.strip_prefix('\\')?, rest))
+}
+
+fn strip_comments(input: &str) -> String {
+    input
+        .lines()
+        .map(|line| {
+            let mut escaped = false;
+            let end = line
+                .char_indices()
+                .find(|(_, c)| {
+                    let comment = *c == '%' && !escaped;
+                    escaped = *c == '\\' && !escaped;
+                    comment
+                })
+                .map_or(line.len(), |(i, _)| i);
+            &line[..end]
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl Macros {
+    /// Macros defined with `\newcommand`, `\renewcommand`, `\def` or `\DeclareMathOperator` in `input`
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        let input = strip_comments(input);
+        let mut macros = BTreeMap::new();
+        let mut rest = input.as_str();
+        while let Some(start) = rest.find('\\') {
+            rest = &rest[start..];
+            let Some(command) = control_sequence(rest) else {
+                break;
+            };
+            let after = rest[command.len() + 1..].trim_start();
+            let (after, starred) = match after.strip_prefix('*') {
+                Some(after) => (after, true),
+                None => (after, false),
+            };
+
+            let invalid = || anyhow!("Invalid definition of \\{command}");
+            rest = match command {
+                "newcommand" | "renewcommand" | "providecommand" => {
+                    let (name, after) = defined_name(after).ok_or_else(invalid)?;
+                    let (arguments, after) = match group(after.trim_start(), '[', ']') {
+                        Some((count, after)) => (count.trim().parse()?, after),
+                        None => (0, after),
+                    };
+                    let (default, after) = match group(after.trim_start(), '[', ']') {
+                        Some((default, after)) => (Some(default.to_string()), after),
+                        None => (None, after),
+                    };
+                    let (body, after) = group(after.trim_start(), '{', '}').ok_or_else(invalid)?;
+                    let definition = Macro {
+                        arguments,
+                        default,
+                        body: body.to_string(),
+                    };
+                    macros.insert(name.to_string(), definition);
+                    after
+                }
+                "def" => {
+                    let (name, after) = defined_name(after).ok_or_else(invalid)?;
+                    let parameters = after.find('{').ok_or_else(invalid)?;
+                    let arguments = after[..parameters].matches('#').count();
+                    let (body, after) =
+                        group(&after[parameters..], '{', '}').ok_or_else(invalid)?;
+                    let definition = Macro {
+                        arguments,
+                        default: None,
+                        body: body.to_string(),
+                    };
+                    macros.insert(name.to_string(), definition);
+                    after
+                }
+                "DeclareMathOperator" => {
+                    let (name, after) = defined_name(after).ok_or_else(invalid)?;
+                    let (operator, after) =
+                        group(after.trim_start(), '{', '}').ok_or_else(invalid)?;
+                    let star = if starred { "*" } else { "" };
+                    let definition = Macro {
+                        arguments: 0,
+                        default: None,
+                        body: format!("\\operatorname{star}{{{operator}}}"),
+                    };
+                    macros.insert(name.to_string(), definition);
+                    after
+                }
+                _ => &rest[command.len() + 1..],
+            };
+        }
+
+        Ok(Self(macros))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        self.0.get(name)
+    }
+
+    /// `input` with every macro replaced by its body, the names of the expanded macros are added to `used`
+    ///
+    /// Fails once [`MAX_EXPANSIONS`] macros are expanded, instead of growing exponentially.
+    pub fn expand(&self, input: &str, used: &mut BTreeSet<String>) -> anyhow::Result<String> {
+        let mut budget = MAX_EXPANSIONS;
+        self.expand_at_depth(input, used, 0, &mut budget)
+    }
+
+    fn expand_at_depth(
+        &self,
+        input: &str,
+        used: &mut BTreeSet<String>,
+        depth: usize,
+        budget: &mut usize,
+    ) -> anyhow::Result<String> {
+        if self.0.is_empty() || depth > MAX_DEPTH {
+            return Ok(input.to_string());
+        }
+
+        let mut output = String::new();
+        let mut rest = input;
+        while let Some(start) = rest.find('\\') {
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(name) = control_sequence(rest) else {
+                break;
+            };
+
+            let after = &rest[name.len() + 1..];
+            match self.0.get(name).and_then(|f| Self::arguments(f, after)) {
+                Some((arguments, after)) => {
+                    *budget = budget.checked_sub(1).ok_or_else(|| {
+                        anyhow!(
+                            "The macros expand more than {MAX_EXPANSIONS} times, \\{name} may be recursive"
+                        )
+                    })?;
+                    used.insert(name.to_string());
+                    let body = Self::substitute(&self.0[name].body, &arguments);
+                    output.push_str(&self.expand_at_depth(&body, used, depth + 1, budget)?);
+                    // `\R x` must not become `\mathbb{R}x`, nor `\Rx` be read as one name
+                    if after.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                        output.push(' ');
+                    }
+                    rest = after;
+                }
+                None => {
+                    output.push_str(&rest[..name.len() + 1]);
+                    rest = after;
+                }
+            }
+        }
+        output.push_str(rest);
+
+        Ok(output)
+    }
+
+    /// Arguments given to `definition` at the start of `input`, and what follows them
+    fn arguments<'i>(definition: &Macro, mut input: &'i str) -> Option<(Vec<String>, &'i str)> {
+        let mut arguments = Vec::new();
+        if let Some(default) = &definition.default {
+            match group(input.trim_start(), '[', ']') {
+                Some((value, after)) => {
+                    arguments.push(value.to_string());
+                    input = after;
+                }
+                None => arguments.push(default.clone()),
+            }
+        } else if definition.arguments > 0 {
+            let (value, after) = argument(input)?;
+            arguments.push(value.to_string());
+            input = after;
+        }
+
+        while arguments.len() < definition.arguments {
+            let (value, after) = argument(input)?;
+            arguments.push(value.to_string());
+            input = after;
+        }
+
+        if definition.arguments == 0 {
+            // Spaces after a control word are not part of the input
+            input = input.strip_prefix(' ').unwrap_or(input);
+        }
+
+        Some((arguments, input))
+    }
+
+    fn substitute(body: &str, arguments: &[String]) -> String {
+        let mut output = String::new();
+        let mut chars = body.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '#' {
+                output.push(c);
+                continue;
+            }
+
+            match chars.peek().copied() {
+                Some('#') => {
+                    chars.next();
+                    output.push('#');
+                }
+                Some(digit @ '1'..='9') => {
+                    chars.next();
+                    let index = digit as usize - '1' as usize;
+                    output.push_str(arguments.get(index).map_or("", String::as_str));
+                }
+                _ => output.push('#'),
+            }
+        }
+
+        output
+    }
+}
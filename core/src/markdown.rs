@@ -1,56 +1,360 @@
-use crate::generator::CurrentPath;
-use base64::{Engine, prelude::BASE64_STANDARD};
+use crate::{
+    config::MathMode,
+    data::Diagnostic,
+    diagram::{self, DiagramKind},
+    generator::CurrentPath,
+    highlight::{self, CodeInfo},
+    svg,
+};
 use comrak::{
     create_formatter,
     html::{ChildRendering, dangerous_url},
     nodes::NodeValue,
 };
 use percent_encoding::percent_decode_str;
-use std::{fmt::Write, io::Read, path::PathBuf};
-
-pub fn render_to_base64<'a>(paths: &'a CurrentPath<'a>, url: &str) -> Option<String> {
-    let percent_decode = PathBuf::from(percent_decode_str(url).decode_utf8().ok()?.into_owned());
-    let joined_path = if percent_decode.is_absolute() {
-        paths
-            .project_path
-            .join(percent_decode.strip_prefix("/").ok()?)
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    /// Played by Anki through a `[sound:...]` tag
+    Audio,
+    Video,
+}
+
+/// Extensions of the files a link plays as a sound, other links are left as they are
+const AUDIO_EXTENSIONS: [&str; 7] = ["mp3", "wav", "ogg", "oga", "m4a", "flac", "opus"];
+
+/// Whether a link to `url` is embedded as a sound, decided before reading anything
+fn is_audio_link(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            AUDIO_EXTENSIONS
+                .iter()
+                .any(|audio| audio.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Where a media file is read from
+enum MediaPath {
+    Repository(PathBuf),
+    /// In one of the `media_dirs` of the settings, read from the disk
+    Allowed(PathBuf),
+}
+
+/// Local file `url` points to, an error when it is outside of the allowed folders
+fn resolve_media(paths: &CurrentPath, url: &str) -> Result<Option<MediaPath>, String> {
+    let has_scheme = url
+        .split_once(':')
+        .is_some_and(|(scheme, _)| scheme.len() > 1 && !scheme.contains('/'));
+    if has_scheme || url.starts_with('#') {
+        return Ok(None);
+    }
+
+    let Ok(decoded) = percent_decode_str(url).decode_utf8() else {
+        return Ok(None);
+    };
+    let decoded = PathBuf::from(decoded.into_owned());
+    let joined_path = match decoded.strip_prefix("/") {
+        Ok(relative) => paths.root.join(relative),
+        Err(_) => paths.file_path.parent().unwrap_or(paths.root).join(decoded),
+    };
+
+    let root = paths.source.canonicalize(paths.root);
+    let canonical = paths.source.canonicalize(&joined_path);
+    if let (Some(root), Some(canonical)) = (&root, &canonical)
+        && canonical.starts_with(root)
+    {
+        return Ok(Some(MediaPath::Repository(canonical.clone())));
+    }
+
+    let Ok(on_disk) = joined_path.canonicalize() else {
+        return Ok(None);
+    };
+
+    let allowed = paths
+        .settings
+        .media_dirs
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .any(|dir| on_disk.starts_with(dir));
+    if allowed {
+        return Ok(Some(MediaPath::Allowed(on_disk)));
+    }
+
+    Err(format!(
+        "{url} is outside of the repository, add its folder to media_dirs to use it"
+    ))
+}
+
+/// Name in Anki of the media at `url`, its content is added to the media of the generator
+///
+/// Nothing is added when the file is not one of `kinds`.
+pub fn register_media(
+    state: &RenderState,
+    url: &str,
+    kinds: &[MediaKind],
+) -> Option<(String, MediaKind)> {
+    let paths = state.paths;
+    let (path, vec) = match resolve_media(paths, url) {
+        Ok(Some(MediaPath::Repository(path))) => {
+            let vec = paths.source.read(&path).ok()?;
+            (path, vec)
+        }
+        Ok(Some(MediaPath::Allowed(path))) => {
+            let vec = std::fs::read(&path).ok()?;
+            (path, vec)
+        }
+        Ok(None) => return None,
+        Err(message) => {
+            state.report(message);
+            return None;
+        }
+    };
+
+    let (kind, extension, vec) = if svg::is_svg(&path, &vec) {
+        (MediaKind::Image, "svg", svg::sanitize(&vec).ok()?)
     } else {
-        paths.file_path.join(percent_decode)
+        let mimetype = infer::get(&vec)?;
+        let kind = match mimetype.matcher_type() {
+            infer::MatcherType::Image => MediaKind::Image,
+            infer::MatcherType::Audio => MediaKind::Audio,
+            infer::MatcherType::Video => MediaKind::Video,
+            _ => return None,
+        };
+        (kind, mimetype.extension(), vec)
     };
+    if !kinds.contains(&kind) {
+        return None;
+    }
+
+    Some((store_media(paths, vec, extension), kind))
+}
+
+/// Name in Anki of the media holding `data`
+fn store_media(paths: &CurrentPath, data: Vec<u8>, extension: &str) -> String {
+    // Named after the content, so an unchanged file keeps its name and is stored once
+    let name = format!("{}.{extension}", &blake3::hash(&data).to_hex()[..16]);
+    paths.media.borrow_mut().insert(name.clone(), data);
+    name
+}
 
-    let mut p = std::fs::File::open(&joined_path).ok()?;
+/// User data of the formatter, for one card field
+pub struct RenderState<'a> {
+    pub paths: &'a CurrentPath<'a>,
+    /// Number given to the next `==...==` cloze deletion
+    pub next_cloze: usize,
+    /// Names of the media files used by the field
+    pub media: Vec<String>,
+    /// Kind of the media of each image or link being rendered, to close it accordingly
+    pub embedded: Vec<Option<MediaKind>>,
+    /// First line of the card, where diagnostics point to
+    pub line: usize,
+    /// Names of the macros expanded in the math of the card
+    pub macros: BTreeSet<String>,
+}
 
-    let mut vec = Vec::new();
-    p.read_to_end(&mut vec).ok()?;
+impl RenderState<'_> {
+    fn report(&self, message: String) {
+        let paths = self.paths;
+        paths.diagnostics.borrow_mut().push(Diagnostic {
+            file: paths
+                .file_path
+                .strip_prefix(paths.root)
+                .unwrap_or(paths.file_path)
+                .display()
+                .to_string(),
+            line: self.line,
+            message,
+        });
+    }
+}
+
+/// `literal` rendered by KaTeX, `None` when it is left to the card template
+fn render_math(state: &RenderState, literal: &str, display_mode: bool) -> Option<String> {
+    let output_type = match state.paths.settings.math {
+        MathMode::Client | MathMode::Mathjax => return None,
+        MathMode::Mathml => katex::OutputType::Mathml,
+        MathMode::Html => katex::OutputType::HtmlAndMathml,
+    };
 
-    let mimetype = infer::get(&vec)?;
+    let opts = katex::Opts::builder()
+        .display_mode(display_mode)
+        .output_type(output_type)
+        .build()
+        .ok()?;
 
-    if !matches!(mimetype.matcher_type(), infer::MatcherType::Image) {
+    katex::render_with_opts(literal, opts)
+        .inspect_err(|error| state.report(format!("Invalid math `{literal}`: {error}")))
+        .ok()
+}
+
+/// Fenced `code` highlighted with the theme of the settings, `None` when it is left as is
+fn render_code(state: &RenderState, code: &str, info: &CodeInfo) -> Option<String> {
+    for range in &info.invalid {
+        state.report(format!(
+            "Invalid line range {{{range}}}, the code block ends at line {}",
+            code.lines().count()
+        ));
+    }
+
+    let name = &state.paths.settings.code_theme;
+    if name == "none" {
         return None;
     }
 
-    Some(format!(
-        "{};base64,{}",
-        mimetype.mime_type(),
-        BASE64_STANDARD.encode(&vec)
-    ))
+    // Reported at the first block only, the setting is the same for every one
+    let theme = state.paths.code_theme.get_or_init(|| {
+        let theme = highlight::theme(name);
+        if theme.is_none() {
+            state.report(format!(
+                "Unknown code theme {name}, available themes are {}",
+                highlight::theme_names().join(", ")
+            ));
+        }
+        theme
+    });
+    let theme = (*theme)?;
+
+    highlight::highlight(code, info, theme)
+}
+
+/// Name of the SVG media drawn from a fenced diagram, `None` when the fence is not one
+fn render_diagram(state: &RenderState, code: &str, language: &str) -> Option<String> {
+    let kind = DiagramKind::from_language(language)?;
+    let paths = state.paths;
+    if !kind.is_drawn(&paths.settings.diagrams) {
+        return None;
+    }
+    let svg = diagram::render(kind, code, &paths.settings.diagrams, paths.root)
+        .and_then(|svg| svg::sanitize(&svg).map_err(|error| error.to_string()))
+        .inspect_err(|error| {
+            state.report(format!(
+                "Cannot draw the {} diagram: {error}",
+                kind.as_str()
+            ))
+        })
+        .ok()?;
+
+    Some(store_media(paths, svg, "svg"))
 }
 
-create_formatter!(CustomMath<&'a CurrentPath<'a>>, {
+create_formatter!(CustomMath<RenderState<'a>>, {
+    NodeValue::CodeBlock(ref ncb) => |context, node, entering| {
+        if !entering || !ncb.fenced {
+            return comrak::html::format_node_default(context, node, entering);
+        }
+
+        let info = CodeInfo::parse(&ncb.info, ncb.literal.lines().count());
+        let language = &info.language;
+        if let Some(name) = render_diagram(&context.user, &ncb.literal, language) {
+            context.cr()?;
+            context.write_str("<p><img src=\"")?;
+            context.escape_href(&name)?;
+            writeln!(context, "\" alt=\"{language} diagram\" /></p>")?;
+            context.user.media.push(name);
+            return Ok(ChildRendering::HTML);
+        }
+        if let Some(html) = render_code(&context.user, &ncb.literal, &info) {
+            context.cr()?;
+            context.write_str(&html)?;
+            return Ok(ChildRendering::HTML);
+        }
+
+        return comrak::html::format_node_default(context, node, entering);
+    },
     NodeValue::Math(ref node) => |context, entering| {
-        let fence = if node.display_math {
-            "$$"
+        if entering {
+            let macros = context.user.paths.macros;
+            let literal = macros
+                .expand(&node.literal, &mut context.user.macros)
+                .unwrap_or_else(|error| {
+                    context.user.report(format!("Cannot expand `{}`: {error}", node.literal));
+                    node.literal.clone()
+                });
+            if let Some(html) = render_math(&context.user, &literal, node.display_math) {
+                context.write_str(&html)?;
+            } else if context.user.paths.settings.math == MathMode::Mathjax {
+                let (open, close) = if node.display_math {
+                    ("\\[", "\\]")
+                } else {
+                    ("\\(", "\\)")
+                };
+                context.write_str(open)?;
+                context.escape(&literal)?;
+                context.write_str(close)?;
+            } else {
+                let fence = if node.display_math {
+                    "$$"
+                } else {
+                    "$"
+                };
+                write!(context, "{fence}{literal}{fence}")?;
+            }
+        }
+    },
+    NodeValue::Highlight => |context, entering| {
+        if entering {
+            let number = context.user.next_cloze;
+            context.user.next_cloze += 1;
+            write!(context, "{{{{c{number}::")?;
         } else {
-            "$"
-        };
-
-        context.write_str(fence)?;
+            context.write_str("}}")?;
+        }
+    },
+    NodeValue::Link(ref nl) => |context, node, entering| {
         if entering {
-            context.write_str(&node.literal)?;
+            let url = &nl.url;
+            let media = (is_audio_link(url)
+                && (context.options.render.r#unsafe || !dangerous_url(url)))
+            .then(|| register_media(&context.user, url, &[MediaKind::Audio]))
+            .flatten();
+            if let Some((name, MediaKind::Audio)) = media {
+                write!(context, "[sound:{name}]")?;
+                context.user.media.push(name);
+                context.user.embedded.push(Some(MediaKind::Audio));
+                return Ok(ChildRendering::Skip);
+            }
+            context.user.embedded.push(None);
+        } else if context.user.embedded.pop().flatten().is_some() {
+            return Ok(ChildRendering::HTML);
         }
+
+        return comrak::html::format_node_default(context, node, entering);
     },
     NodeValue::Image(ref nl) => |context, node, entering| {
         if entering {
+            let url = &nl.url;
+            let media = (context.options.render.r#unsafe || !dangerous_url(url))
+                .then(|| {
+                    let kinds = [MediaKind::Image, MediaKind::Audio, MediaKind::Video];
+                    register_media(&context.user, url, &kinds)
+                })
+                .flatten();
+            context.user.embedded.push(media.as_ref().map(|(_, kind)| *kind));
+            match media {
+                Some((name, MediaKind::Audio)) => {
+                    write!(context, "[sound:{name}]")?;
+                    context.user.media.push(name);
+                    return Ok(ChildRendering::Skip);
+                }
+                Some((name, MediaKind::Video)) => {
+                    context.write_str("<video controls src=\"")?;
+                    context.escape_href(&name)?;
+                    context.write_str("\">")?;
+                    context.user.media.push(name);
+                    return Ok(ChildRendering::Plain);
+                }
+                _ => {}
+            }
+
             if context.options.render.figure_with_caption {
                 context.write_str("<figure>")?;
             }
@@ -62,11 +366,11 @@ create_formatter!(CustomMath<&'a CurrentPath<'a>>, {
                 }
             }
             context.write_str(" src=\"")?;
-            let url = &nl.url;
-            if context.options.render.r#unsafe || !dangerous_url(url) {
-                if let Some(base64) = render_to_base64(context.user, url) {
-                    context.write_str(&base64)?;
-                } else if let Some(rewriter) = &context.options.extension.image_url_rewriter {
+            if let Some((name, _)) = media {
+                context.escape_href(&name)?;
+                context.user.media.push(name);
+            } else if context.options.render.r#unsafe || !dangerous_url(url) {
+                if let Some(rewriter) = &context.options.extension.image_url_rewriter {
                     context.escape_href(&rewriter.to_html(&nl.url))?;
                 } else {
                     context.escape_href(url)?;
@@ -75,6 +379,14 @@ create_formatter!(CustomMath<&'a CurrentPath<'a>>, {
             context.write_str("\" alt=\"")?;
             return Ok(ChildRendering::Plain);
         }
+        match context.user.embedded.pop().flatten() {
+            Some(MediaKind::Audio) => return Ok(ChildRendering::HTML),
+            Some(MediaKind::Video) => {
+                context.write_str("</video>")?;
+                return Ok(ChildRendering::HTML);
+            }
+            _ => {}
+        }
         if !nl.title.is_empty() {
             context.write_str("\" title=\"")?;
             context.escape(&nl.title)?;
@@ -0,0 +1,126 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::git::{EntryKind, Git};
+
+/// Where the markdown files and their media are read from
+pub trait Source {
+    /// Files directly inside `dir`
+    fn files(&self, dir: &Path) -> Vec<PathBuf>;
+    fn is_dir(&self, path: &Path) -> bool;
+    fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>>;
+    /// Absolute path of `path` with its symlinks resolved, `None` when it does not exist
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf>;
+
+    fn read_to_string(&self, path: &Path) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.read(path)?)?)
+    }
+}
+
+/// Files as they are on disk
+pub struct WorkTree;
+
+impl Source for WorkTree {
+    fn files(&self, dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|dir_entry| dir_entry.file_type().map(|f| f.is_file()).unwrap_or(false))
+            .map(|f| f.path())
+            .collect()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        Ok(std::fs::read(path)?)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        path.canonicalize().ok()
+    }
+}
+
+/// Files as they are in the tree of a commit, without touching the worktree
+pub struct GitTree<'a> {
+    git: &'a Git,
+    root: PathBuf,
+    commit: String,
+}
+
+impl<'a> GitTree<'a> {
+    pub fn new(git: &'a Git, commit: &str) -> Self {
+        Self {
+            git,
+            root: PathBuf::from(&git.repo),
+            commit: commit.to_string(),
+        }
+    }
+
+    /// Path inside the tree, `None` when it leaves the repository
+    fn tree_path(&self, path: &Path) -> Option<PathBuf> {
+        let path = path.strip_prefix(&self.root).unwrap_or(path);
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => normalized.push(part),
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        return None;
+                    }
+                }
+                Component::CurDir | Component::RootDir => {}
+                Component::Prefix(_) => return None,
+            }
+        }
+
+        Some(normalized)
+    }
+}
+
+impl Source for GitTree<'_> {
+    fn files(&self, dir: &Path) -> Vec<PathBuf> {
+        let Some(dir) = self.tree_path(dir) else {
+            return Vec::new();
+        };
+
+        self.git
+            .ls_tree(&self.commit, &dir)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.is_blob())
+            .map(|entry| self.root.join(entry.path))
+            .collect()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let Some(tree_path) = self.tree_path(path) else {
+            return false;
+        };
+
+        tree_path.as_os_str().is_empty()
+            || self
+                .git
+                .object_kind(&self.commit, &tree_path)
+                .is_ok_and(|kind| kind == EntryKind::Dir)
+    }
+
+    fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        let Some(tree_path) = self.tree_path(path) else {
+            return Err(anyhow::anyhow!(
+                "{} is outside of the repository",
+                path.display()
+            ));
+        };
+
+        Ok(self.git.cat_file(&self.commit, &tree_path)?)
+    }
+
+    /// Git does not follow symlinks, so the path only has to stay in the tree
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        self.tree_path(path)
+            .map(|tree_path| self.root.join(tree_path))
+    }
+}
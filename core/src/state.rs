@@ -0,0 +1,43 @@
+use std::path::{Path, PathBuf};
+
+/// What was already delivered to Anki for a repository
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct SyncState {
+    pub last_commit: Option<String>,
+}
+
+impl SyncState {
+    pub fn path_for(slug: &str) -> PathBuf {
+        PathBuf::from(format!("{slug}.state.toml"))
+    }
+
+    /// State of a sync whose output Anki has not confirmed yet
+    pub fn pending_path_for(slug: &str) -> PathBuf {
+        PathBuf::from(format!("{slug}.state.pending.toml"))
+    }
+
+    /// Make the pending state of `slug` the delivered one, once Anki holds the output
+    pub fn commit(slug: &str) -> anyhow::Result<()> {
+        let pending = Self::pending_path_for(slug);
+        if pending.exists() {
+            std::fs::rename(pending, Self::path_for(slug))?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("toml.tmp");
+        std::fs::write(&tmp, toml::to_string(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use quick_xml::{
+    Reader, Writer,
+    events::{BytesStart, Event, attributes::Attribute},
+};
+
+/// SVG is text, so `infer` cannot tell it apart: trust the extension, else look for the root element
+pub fn is_svg(path: &Path, data: &[u8]) -> bool {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+    {
+        return true;
+    }
+
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// Value of `attribute` as a browser reads it: unescaped, lowercase and without blanks
+///
+/// `None` when it cannot be unescaped, such an attribute is dropped.
+fn read_value(attribute: &Attribute) -> Option<String> {
+    let value = attribute.unescape_value().ok()?;
+    Some(
+        value
+            .chars()
+            .filter(|c| !c.is_whitespace() && !c.is_control())
+            .flat_map(char::to_lowercase)
+            .collect(),
+    )
+}
+
+/// Value of the attribute of `element` with the local name `name`
+fn attribute_value(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .with_checks(false)
+        .flatten()
+        .find(|attribute| {
+            attribute
+                .key
+                .local_name()
+                .as_ref()
+                .eq_ignore_ascii_case(name)
+        })
+        .and_then(|attribute| read_value(&attribute))
+}
+
+/// Elements left out with their content
+///
+/// Scripts, HTML embedded by `foreignObject`, animations that could turn a link into
+/// a `javascript:` url and `use` of another document, whose content is not checked.
+fn is_dropped(element: &BytesStart) -> bool {
+    let name = element.local_name();
+    let name = name.as_ref();
+    if name.eq_ignore_ascii_case(b"script") || name.eq_ignore_ascii_case(b"foreignobject") {
+        return true;
+    }
+
+    if name.eq_ignore_ascii_case(b"animate") || name.eq_ignore_ascii_case(b"set") {
+        return attribute_value(element, b"attributename")
+            .is_none_or(|target| target == "href" || target.ends_with(":href"));
+    }
+
+    if name.eq_ignore_ascii_case(b"use") {
+        return attribute_value(element, b"href").is_some_and(|href| !href.starts_with('#'));
+    }
+
+    false
+}
+
+/// `element` without its event handlers and `javascript:` urls
+fn clean_attributes(element: &BytesStart) -> BytesStart<'static> {
+    let mut clean = element.to_owned();
+    clean.clear_attributes();
+    for attribute in element.attributes().with_checks(false).flatten() {
+        let name = attribute.key.local_name();
+        if name.as_ref().len() > 2 && name.as_ref()[..2].eq_ignore_ascii_case(b"on") {
+            continue;
+        }
+
+        if read_value(&attribute).is_none_or(|value| value.starts_with("javascript:")) {
+            continue;
+        }
+
+        clean.push_attribute(attribute);
+    }
+
+    clean
+}
+
+/// Copy of the SVG `data` without scripts, event handlers, doctype and the [dropped](is_dropped) elements
+pub fn sanitize(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(data);
+    let mut writer = Writer::new(Vec::new());
+    let mut dropped_depth = 0usize;
+
+    loop {
+        let event = reader.read_event()?;
+        if dropped_depth > 0 {
+            match event {
+                Event::Start(_) => dropped_depth += 1,
+                Event::End(_) => dropped_depth -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(ref element) if is_dropped(element) => dropped_depth = 1,
+            Event::Empty(ref element) if is_dropped(element) => {}
+            Event::Start(ref element) => {
+                writer.write_event(Event::Start(clean_attributes(element)))?
+            }
+            Event::Empty(ref element) => {
+                writer.write_event(Event::Empty(clean_attributes(element)))?
+            }
+            // Could declare entities
+            Event::DocType(_) => {}
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+
+    Ok(writer.into_inner())
+}
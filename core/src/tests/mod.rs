@@ -1,9 +1,17 @@
 use std::path::Path;
+use std::process::Command;
 use std::{fs, io};
 use tempfile::TempDir;
 
+mod test_apkg;
+mod test_auth;
+// The original generator tests borrow their `&str` inputs again
+#[allow(clippy::needless_borrow)]
 mod test_generator;
+mod test_inventory;
+mod test_state;
 mod test_subdeck;
+mod test_svg;
 mod test_updater;
 
 // https://stackoverflow.com/a/65192210
@@ -34,6 +42,58 @@ impl FakeRepo {
 
 impl AsRef<Path> for FakeRepo {
     fn as_ref(&self) -> &Path {
-        return self.0.path();
+        self.0.path()
+    }
+}
+
+/// Git repository built from scratch, for tests that need specific history
+struct TestRepo(TempDir);
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let repo = Self(TempDir::new().unwrap());
+        repo.git(&["init", "--quiet", "--initial-branch", "main"]);
+        repo
+    }
+
+    /// Clone of this repository, tracking it as `origin`
+    pub fn clone_repo(&self) -> Self {
+        let clone = Self(TempDir::new().unwrap());
+        clone.git(&["clone", "--quiet", &self.path(), "."]);
+        clone
+    }
+
+    pub fn path(&self) -> String {
+        self.0.path().to_str().unwrap().to_string()
+    }
+
+    pub fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.name=gencore",
+                "-c",
+                "user.email=gencore@localhost",
+                "-c",
+                "commit.gpgsign=false",
+            ])
+            .args(args)
+            .current_dir(self.0.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?} failed");
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    pub fn write(&self, path: &str, content: &str) {
+        let path = self.0.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    pub fn commit(&self, message: &str) -> String {
+        self.git(&["add", "-A"]);
+        self.git(&["commit", "--quiet", "--allow-empty", "-m", message]);
+        self.git(&["rev-parse", "HEAD"])
     }
 }
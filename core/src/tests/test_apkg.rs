@@ -0,0 +1,224 @@
+use std::io::Read;
+
+use rstest::rstest;
+use tempfile::TempDir;
+
+use crate::{
+    apkg::Package,
+    config::{KatexAssets, Settings},
+    data::{DeckOutput, Output},
+    generator::Generator,
+    init::Init,
+    source::WorkTree,
+    with_guids,
+};
+
+fn open_collection(
+    archive: &mut zip::ZipArchive<std::fs::File>,
+    dir: &TempDir,
+) -> rusqlite::Connection {
+    let collection_path = dir.path().join("collection.anki2");
+    let mut collection = Vec::new();
+    archive
+        .by_name("collection.anki2")
+        .unwrap()
+        .read_to_end(&mut collection)
+        .unwrap();
+    std::fs::write(&collection_path, collection).unwrap();
+
+    rusqlite::Connection::open(&collection_path).unwrap()
+}
+
+#[rstest]
+pub fn test_write_apkg() {
+    let path = std::path::Path::new("./tests/test");
+    let generated = Init::new("", "", path, &Settings::default())
+        .generate()
+        .unwrap();
+    let generated = with_guids(generated, "test");
+    let dir = TempDir::new().unwrap();
+    let apkg_path = dir.path().join("deck.apkg");
+    Package::new(&generated)
+        .with_katex(KatexAssets::Scripts)
+        .write_to_file(&apkg_path)
+        .unwrap();
+
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&apkg_path).unwrap()).unwrap();
+    let mut media = String::new();
+    archive
+        .by_name("media")
+        .unwrap()
+        .read_to_string(&mut media)
+        .unwrap();
+    assert_eq!(media, "{}");
+
+    let conn = open_collection(&mut archive, &dir);
+    let notes: i64 = conn
+        .query_row("SELECT count(*) FROM notes", [], |row| row.get(0))
+        .unwrap();
+    let cards: i64 = conn
+        .query_row("SELECT count(*) FROM cards", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(notes, 3);
+    assert_eq!(cards, 3);
+    let due: Vec<i64> = conn
+        .prepare("SELECT due FROM cards ORDER BY due")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(due, [1, 2, 3]);
+
+    let decks: String = conn
+        .query_row("SELECT decks FROM col", [], |row| row.get(0))
+        .unwrap();
+    assert!(decks.contains("\"a::b::c\""));
+
+    let fields: String = conn
+        .query_row(
+            "SELECT flds FROM notes WHERE guid = ?1",
+            [&generated.get("a::b::c").unwrap().added[0].guid],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(
+        fields,
+        "<h2>Blahaj</h2>\x1f<p>cutest shark</p>\x1fd9a617e1bab933800c1df24cbe1a6cc543b1e0dcfd414346f3c2d7a07647d0c2"
+    );
+
+    // The math of the cards is left to KaTeX, as in the note types of the add-on
+    let models: String = conn
+        .query_row("SELECT models FROM col", [], |row| row.get(0))
+        .unwrap();
+    let models: serde_json::Value = serde_json::from_str(&models).unwrap();
+    let basic = models
+        .as_object()
+        .unwrap()
+        .values()
+        .find(|model| model["name"] == "Ankill")
+        .unwrap();
+    assert_eq!(
+        basic["tmpls"][0]["qfmt"],
+        format!("{{{{Recto}}}}{}", KatexAssets::Scripts.html())
+    );
+}
+
+#[rstest]
+pub fn test_write_cloze_apkg() {
+    let path = std::path::Path::new("./tests");
+    let added = Generator::new(&WorkTree, &Settings::default(), path).generate_card_from_input(
+        "## Capitals\n==Paris== and ==Rome==\n",
+        path,
+        None,
+    );
+    let decks = Output::from([(
+        "cloze".to_string(),
+        DeckOutput {
+            added,
+            ..Default::default()
+        },
+    )]);
+
+    let dir = TempDir::new().unwrap();
+    let apkg_path = dir.path().join("deck.apkg");
+    Package::new(&decks).write_to_file(&apkg_path).unwrap();
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&apkg_path).unwrap()).unwrap();
+    let conn = open_collection(&mut archive, &dir);
+
+    let ords: Vec<i64> = conn
+        .prepare("SELECT ord FROM cards ORDER BY ord")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(ords, vec![0, 1]);
+
+    let models: String = conn
+        .query_row("SELECT models FROM col", [], |row| row.get(0))
+        .unwrap();
+    assert!(models.contains("{{cloze:Texte}}"));
+}
+
+#[rstest]
+pub fn test_write_reversed_apkg() {
+    let path = std::path::Path::new("./tests");
+    let added = Generator::new(&WorkTree, &Settings::default(), path).generate_card_from_input(
+        "## Dog {.reversed}\nChien\n\n## Cat {.optional-reversed}\nChat\n",
+        path,
+        None,
+    );
+    let decks = Output::from([(
+        "reversed".to_string(),
+        DeckOutput {
+            added,
+            ..Default::default()
+        },
+    )]);
+
+    let dir = TempDir::new().unwrap();
+    let apkg_path = dir.path().join("deck.apkg");
+    Package::new(&decks).write_to_file(&apkg_path).unwrap();
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&apkg_path).unwrap()).unwrap();
+    let conn = open_collection(&mut archive, &dir);
+
+    let cards: i64 = conn
+        .query_row("SELECT count(*) FROM cards", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(cards, 4);
+
+    let fields: String = conn
+        .query_row("SELECT flds FROM notes WHERE sfld = 'Cat'", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert!(fields.ends_with("\x1fy"));
+
+    let models: String = conn
+        .query_row("SELECT models FROM col", [], |row| row.get(0))
+        .unwrap();
+    assert!(models.contains("Ankill (optional reversed)"));
+    assert!(models.contains("{{#Inverser}}{{Verso}}{{/Inverser}}"));
+}
+
+#[rstest]
+pub fn test_write_media_apkg() {
+    let path = std::path::Path::new("./tests");
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, path);
+    let added = generator.generate_card_from_input(
+        "## Shark\n![](blahaj.png)\n",
+        &path.join("test.md"),
+        None,
+    );
+    let decks = Output::from([(
+        "media".to_string(),
+        DeckOutput {
+            added,
+            media: generator.take_media(),
+            ..Default::default()
+        },
+    )]);
+
+    let dir = TempDir::new().unwrap();
+    let apkg_path = dir.path().join("deck.apkg");
+    Package::new(&decks).write_to_file(&apkg_path).unwrap();
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&apkg_path).unwrap()).unwrap();
+
+    let mut media = String::new();
+    archive
+        .by_name("media")
+        .unwrap()
+        .read_to_string(&mut media)
+        .unwrap();
+    assert_eq!(media, r#"{"0":"711233b7eeaaa59c.png"}"#);
+
+    let mut data = Vec::new();
+    archive
+        .by_name("0")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, std::fs::read(path.join("blahaj.png")).unwrap());
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use rstest::rstest;
+
+use crate::{
+    config::{Auth, GitBackend},
+    git::{
+        Git, GitError, Pin,
+        auth::{self, SshIdentity},
+    },
+    tests::TestRepo,
+};
+
+/// Serves the repositories of this machine as `ssh` would, only to clients giving a key
+const SSH_STAND_IN: &str = r#"#!/bin/sh
+case " $* " in
+    *" BatchMode=yes "*) ;;
+    *) echo "ssh could prompt from inside Anki" >&2; exit 255 ;;
+esac
+case " $* " in
+    *" -i "*) ;;
+    *) echo "git@localhost: Permission denied (publickey)." >&2; exit 255 ;;
+esac
+for command; do :; done
+exec sh -c "$command"
+"#;
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    data.chunks(3)
+        .flat_map(|chunk| {
+            let bits = chunk
+                .iter()
+                .fold(0u32, |bits, byte| bits << 8 | u32::from(*byte))
+                << (8 * (3 - chunk.len()));
+            (0..4).map(move |i| match i <= chunk.len() {
+                true => char::from(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize]),
+                false => '=',
+            })
+        })
+        .collect()
+}
+
+/// Answer one request with `git http-backend`, when it gives the `authorization` expected
+fn answer(stream: TcpStream, root: &Path, authorization: &str) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut request = request.split_whitespace().map(str::to_string);
+    let (method, target) = (
+        request.next().unwrap_or_default(),
+        request.next().unwrap_or_default(),
+    );
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let mut stream = stream;
+    if headers.get("authorization").map(String::as_str) != Some(authorization) {
+        return stream.write_all(
+            b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"test\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+
+    let mut body = Vec::new();
+    if headers
+        .get("transfer-encoding")
+        .is_some_and(|encoding| encoding == "chunked")
+    {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim(), 16).unwrap_or_default();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = headers.get("content-length") {
+        body.resize(length.parse().unwrap_or_default(), 0);
+        reader.read_exact(&mut body)?;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let header = |name: &str| headers.get(name).cloned().unwrap_or_default();
+    let mut backend = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env("REQUEST_METHOD", &method)
+        .env("CONTENT_TYPE", header("content-type"))
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .env("HTTP_CONTENT_ENCODING", header("content-encoding"))
+        .env("GIT_PROTOCOL", header("git-protocol"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    backend.stdin.take().unwrap().write_all(&body)?;
+    let output = backend.wait_with_output()?.stdout;
+
+    let split = output
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or_default();
+    let (head, content) = (
+        String::from_utf8_lossy(&output[..split]),
+        &output[split + 4..],
+    );
+    let status = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Status:"))
+        .map_or("200 OK", str::trim);
+    let mut response = format!("HTTP/1.1 {status}\r\n");
+    for line in head.lines().filter(|line| !line.starts_with("Status:")) {
+        response.push_str(&format!("{line}\r\n"));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        content.len()
+    ));
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(content)
+}
+
+/// Url of `repo` served over HTTP, only to `username` with `password`
+fn serve_http(repo: &TestRepo, username: &str, password: &str) -> String {
+    let path = PathBuf::from(repo.path());
+    let root = path.parent().unwrap().to_path_buf();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/{}",
+        listener.local_addr().unwrap(),
+        path.file_name().unwrap().to_string_lossy()
+    );
+    let authorization = format!(
+        "Basic {}",
+        base64(format!("{username}:{password}").as_bytes())
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = answer(stream, &root, &authorization);
+        }
+    });
+    url
+}
+
+#[rstest]
+pub fn test_missing_credentials(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    upstream.commit("init");
+    let url = format!("file://{}", upstream.path());
+    let dir = tempfile::TempDir::new().unwrap();
+
+    let token = Auth {
+        token_env: Some("GENCORE_TEST_UNSET_TOKEN".to_string()),
+        ..Default::default()
+    };
+    let path = dir.path().join("token");
+    assert!(matches!(
+        Git::clone(&url, path.to_str().unwrap(), backend, &token, &Pin::Default),
+        Err(GitError::TokenNotSet(name)) if name == "GENCORE_TEST_UNSET_TOKEN"
+    ));
+
+    let key = Auth {
+        ssh_key: Some(dir.path().join("missing_key")),
+        ..Default::default()
+    };
+    let clone = upstream.clone_repo();
+    assert!(matches!(
+        Git::open_with_auth(clone.path(), backend, key).update(&Pin::Default),
+        Err(GitError::KeyNotFound(path)) if path == dir.path().join("missing_key")
+    ));
+}
+
+#[rstest]
+#[case("!f() { test \"$1\" = get && grep -q host=git.example.org && echo username=alice && echo password=secret; }; f", Some(("alice", "secret")))]
+#[case("!f() { echo password=secret; }; f", Some(("git", "secret")))]
+#[case("!true", None)]
+pub fn test_credential_helper(#[case] helper: &str, #[case] expected: Option<(&str, &str)>) {
+    let credentials = auth::fill(helper, "https://git.example.org/course.git").unwrap();
+    assert_eq!(
+        credentials,
+        expected.map(|(username, password)| (username.to_string(), password.to_string()))
+    );
+}
+
+#[rstest]
+pub fn test_ssh_key_is_used(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    upstream.commit("init");
+    let clone = upstream.clone_repo();
+    let dir = tempfile::TempDir::new().unwrap();
+    let ssh = dir.path().join("ssh");
+    std::fs::write(&ssh, SSH_STAND_IN).unwrap();
+    std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let key = dir.path().join("id_course");
+    std::fs::write(&key, "").unwrap();
+    clone.git(&["config", "core.sshCommand", ssh.to_str().unwrap()]);
+    let url = format!("ssh://localhost{}", upstream.path());
+    clone.git(&["remote", "set-url", "origin", &url]);
+    upstream.write("deck/cards.md", "## A\nSecond\n");
+    let second = upstream.commit("second");
+    let auth = Auth {
+        ssh_key: Some(PathBuf::from(&key)),
+        ..Default::default()
+    };
+
+    if backend == GitBackend::Builtin {
+        // libgit2 speaks SSH itself and no server runs here, the key only has to reach the transport
+        assert!(matches!(
+            Git::open_with_auth(clone.path(), backend, auth).update(&Pin::Default),
+            Err(GitError::Libgit2(_))
+        ));
+        return;
+    }
+
+    assert!(matches!(
+        Git::open(clone.path(), backend).update(&Pin::Default),
+        Err(GitError::Auth(message)) if message.contains("Permission denied")
+    ));
+
+    let update = Git::open_with_auth(clone.path(), backend, auth)
+        .update(&Pin::Default)
+        .unwrap();
+    assert_eq!(update.to_commit, second);
+}
+
+#[rstest]
+pub fn test_token(#[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    upstream.commit("init");
+    let url = serve_http(&upstream, "oauth2", "secret");
+    let dir = tempfile::TempDir::new().unwrap();
+    let name = format!("GENCORE_TEST_TOKEN_{backend:?}");
+    let auth = Auth {
+        token_env: Some(name.clone()),
+        username: Some("oauth2".to_string()),
+        ..Default::default()
+    };
+
+    // SAFETY: the variable is only read by this test
+    unsafe { std::env::set_var(&name, "secret") };
+    let path = dir.path().join("token");
+    Git::clone(&url, path.to_str().unwrap(), backend, &auth, &Pin::Default).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(path.join("deck/cards.md")).unwrap(),
+        "## A\nFirst\n"
+    );
+
+    // SAFETY: the variable is only read by this test
+    unsafe { std::env::set_var(&name, "expired") };
+    let path = dir.path().join("expired");
+    assert!(matches!(
+        Git::clone(&url, path.to_str().unwrap(), backend, &auth, &Pin::Default),
+        Err(GitError::Auth(_))
+    ));
+}
+
+#[rstest]
+#[case(
+    "!f() { test \"$1\" = get && echo username=alice && echo password=secret; }; f",
+    true
+)]
+#[case("!f() { echo username=alice; echo password=wrong; }; f", false)]
+pub fn test_credential_helper_is_used(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+    #[case] helper: &str,
+    #[case] accepted: bool,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    upstream.commit("init");
+    let url = serve_http(&upstream, "alice", "secret");
+    let dir = tempfile::TempDir::new().unwrap();
+    let auth = Auth {
+        credential_helper: Some(helper.to_string()),
+        ..Default::default()
+    };
+
+    let path = dir.path().join("clone");
+    let clone = Git::clone(&url, path.to_str().unwrap(), backend, &auth, &Pin::Default);
+    if accepted {
+        assert!(clone.is_ok());
+        assert!(path.join("deck/cards.md").is_file());
+    } else {
+        assert!(matches!(clone, Err(GitError::Auth(_))));
+    }
+}
+
+#[rstest]
+pub fn test_ssh_identities() {
+    let home = tempfile::TempDir::new().unwrap();
+    let ssh = home.path().join(".ssh");
+    std::fs::create_dir(&ssh).unwrap();
+    std::fs::write(ssh.join("id_rsa"), "").unwrap();
+    std::fs::write(ssh.join("id_ed25519"), "").unwrap();
+
+    let defaults = auth::ssh_identities(&Auth::default(), true, Some(home.path())).unwrap();
+    assert_eq!(
+        defaults,
+        vec![
+            SshIdentity::Agent,
+            SshIdentity::Key(ssh.join("id_ed25519")),
+            SshIdentity::Key(ssh.join("id_rsa")),
+        ]
+    );
+    let without_agent = auth::ssh_identities(&Auth::default(), false, None).unwrap();
+    assert!(without_agent.is_empty());
+
+    let key = Auth {
+        ssh_key: Some(ssh.join("id_rsa")),
+        ..Default::default()
+    };
+    assert_eq!(
+        auth::ssh_identities(&key, true, Some(home.path())).unwrap(),
+        vec![SshIdentity::Key(ssh.join("id_rsa"))]
+    );
+}
@@ -1,8 +1,15 @@
-use std::{io::Read, path::Path};
+use std::{collections::BTreeSet, io::Read, path::Path};
 
 use rstest::{fixture, rstest};
 
-use crate::generator::Generator;
+use crate::{
+    config::{Diagrams, Extensions, MathMode, Settings},
+    data::CardKind,
+    generator::Generator,
+    highlight::CodeInfo,
+    macros::Macros,
+    source::WorkTree,
+};
 
 #[fixture]
 pub fn get_test_folder<'a>() -> &'static Path {
@@ -14,10 +21,8 @@ pub fn test(get_test_folder: &Path) {
     let mut file = std::fs::File::open(get_test_folder.join("test.md")).unwrap();
     let mut input = String::new();
     file.read_to_string(&mut input).unwrap();
-    let output = Generator {
-        subproject_path: get_test_folder,
-    }
-    .generate_card_from_input(&input, get_test_folder);
+    let output = Generator::new(&WorkTree, &Settings::default(), get_test_folder)
+        .generate_card_from_input(&input, get_test_folder, None);
     let first = &output[0];
     let second = &output[1];
     assert_eq!(first.front, "<h2>Nya</h2>");
@@ -46,10 +51,8 @@ pub fn test_output(
     #[case] front: &str,
     #[case] back: &str,
 ) {
-    let output = Generator {
-        subproject_path: get_test_folder,
-    }
-    .generate_card_from_input(&input, get_test_folder);
+    let output = Generator::new(&WorkTree, &Settings::default(), get_test_folder)
+        .generate_card_from_input(&input, get_test_folder, None);
     let first = &output[0];
     assert_eq!(first.front, front);
     assert_eq!(first.back, back);
@@ -65,10 +68,8 @@ $$ Blahaj\<Shark> > boykisser\<Cat> $$
 $$ Blahaj\<Shark> > boykisser\<Cat> $$
     "#;
 
-    let output = Generator {
-        subproject_path: get_test_folder,
-    }
-    .generate_card_from_input(&input, get_test_folder);
+    let output = Generator::new(&WorkTree, &Settings::default(), get_test_folder)
+        .generate_card_from_input(&input, get_test_folder, None);
     println!("{:#?}", output);
     let first = &output[0];
     let second = &output[1];
@@ -83,39 +84,776 @@ $$ Blahaj\<Shark> > boykisser\<Cat> $$
 }
 
 #[rstest]
-pub fn test_image_base64(get_test_folder: &Path) {
-    let input = r#"
-## Is Blahaj > boykisser
-![title](blahaj.png)
-    "#;
-    let output = Generator {
-        subproject_path: get_test_folder,
-    }
-    .generate_card_from_input(&input, get_test_folder);
-    println!("{:#?}", output);
+#[case("blahaj.png")]
+#[case("/blahaj.png")]
+pub fn test_image_media(get_test_folder: &Path, #[case] url: &str) {
+    let input =
+        format!("\n## Is Blahaj > boykisser\n![title]({url})\n\n## Again\n![again]({url})\n");
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(&input, &get_test_folder.join("test.md"), None);
     let first = &output[0];
     assert_eq!(first.front, "<h2>Is Blahaj &gt; boykisser</h2>");
     assert_eq!(
         first.back,
-        r#"<p><img src="image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAQAAAAECAYAAACp8Z5+AAAAAXNSR0IB2cksfwAAAARnQU1BAACxjwv8YQUAAAAgY0hSTQAAeiYAAICEAAD6AAAAgOgAAHUwAADqYAAAOpgAABdwnLpRPAAAADhJREFUCNc1yDERgEAQBMGZLUIMoBkBnyMOA8TcEtFhe63zTrIDvDNs6DEtACpRn/4BpC0qANPyAYb0EsnPnK8eAAAAAElFTkSuQmCC" alt="title" /></p>"#
+        r#"<p><img src="711233b7eeaaa59c.png" alt="title" /></p>"#
+    );
+    assert_eq!(first.media, vec!["711233b7eeaaa59c.png"]);
+    assert_eq!(output[1].media, first.media);
+
+    let media = generator.take_media();
+    assert_eq!(media.len(), 1);
+    assert_eq!(
+        media["711233b7eeaaa59c.png"],
+        std::fs::read(get_test_folder.join("blahaj.png")).unwrap()
     );
 }
 
 #[rstest]
-pub fn test_absolute_path_image_base64(get_test_folder: &Path) {
+pub fn test_card_id(get_test_folder: &Path) {
     let input = r#"
-## Is Blahaj > boykisser
-![title](/blahaj.png)
+## Question {#my-id}
+Answer
+
+## Question
+Answer
+
+## Question
+Another answer
     "#;
-    let output = Generator {
-        subproject_path: get_test_folder,
-    }
-    .generate_card_from_input(&input, get_test_folder);
-    println!("{:#?}", output);
-    let first = &output[0];
-    assert_eq!(first.front, "<h2>Is Blahaj &gt; boykisser</h2>");
+
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, &get_test_folder.join("a.md"), None);
+    assert_eq!(output[0].id, "my-id");
+    assert_eq!(output[0].front, "<h2>Question</h2>");
+    // Told apart, and reported, with the other cards of the deck
+    assert_eq!(output[2].id, output[1].id);
+
+    let edited = generator.generate_card_from_input(
+        "## Question\nFixed answer",
+        &get_test_folder.join("a.md"),
+        None,
+    );
+    assert_eq!(edited[0].id, output[1].id);
+    assert_ne!(edited[0].hash, output[1].hash);
+
+    let other_file = generator.generate_card_from_input(input, &get_test_folder.join("b.md"), None);
+    assert_ne!(other_file[1].id, output[1].id);
+}
+
+#[rstest]
+pub fn test_duplicate_ids_across_files() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(dir.path().join("a.md"), "## A {#same}\nFirst\n").unwrap();
+    std::fs::write(
+        dir.path().join("b.md"),
+        "## B {#same}\nSecond\n\n## C {#same-1}\nThird\n",
+    )
+    .unwrap();
+
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, dir.path());
+    let ids = generator
+        .generate_card_from_folder("deck")
+        .into_iter()
+        .map(|card| card.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, ["same", "same-1", "same-1-1"]);
+
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].file, "b.md");
+    assert_eq!(diagnostics[0].line, 1);
+    assert!(diagnostics[0].message.contains("already used in a.md"));
+}
+
+#[rstest]
+pub fn test_duplicate_ids_in_a_file() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(
+        dir.path().join("a.md"),
+        "## A {#same}\nFirst\n\n## B {#same}\nSecond\n",
+    )
+    .unwrap();
+
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, dir.path());
+    let ids = generator
+        .generate_card_from_folder("deck")
+        .into_iter()
+        .map(|card| card.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, ["same", "same-1"]);
+
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].file, "a.md");
+    assert_eq!(diagnostics[0].line, 4);
+    assert!(diagnostics[0].message.contains("already used in a.md"));
+}
+
+#[rstest]
+pub fn test_segmentation(get_test_folder: &Path) {
+    let input = r#"# Title
+Preamble
+
+## C# and F#
+```sh
+## not a heading
+```
+
+### Details
+More
+
+Setext card
+-----------
+Body
+# Chapter
+Not a card
+"#;
+
+    let output = Generator::new(&WorkTree, &Settings::default(), get_test_folder)
+        .generate_card_from_input(input, get_test_folder, None);
+    assert_eq!(output.len(), 2);
+    assert_eq!(output[0].front, "<h2>C# and F#</h2>");
+    assert!(output[0].back.contains("## not a heading"));
+    assert!(output[0].back.contains("<h3>Details</h3>"));
+    assert_eq!(output[0].lines, (4, 10));
+    assert_eq!(output[1].front, "<h2>Setext card</h2>");
+    assert_eq!(output[1].back, "<p>Body</p>");
+    assert_eq!(output[1].lines, (12, 14));
+}
+
+#[rstest]
+pub fn test_heading_level(get_test_folder: &Path) {
+    let input = "## Chapter\n### First\nA\n### Second\nB\n";
+    let settings = Settings {
+        heading_level: 3,
+        ..Default::default()
+    };
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    assert_eq!(output.len(), 2);
+    assert_eq!(output[0].front, "<h3>First</h3>");
+    assert_eq!(output[1].back, "<p>B</p>");
+    assert_eq!(output[1].lines, (4, 5));
+}
+
+#[rstest]
+pub fn test_cloze(get_test_folder: &Path) {
+    let input = r#"
+## Capitals
+The capital of France is ==Paris== and of Italy {{c1::Rome}}.
+Also ==Berlin==.
+
+## With extra
+`a == b` is ==equality==
+%
+Comparison operator
+
+## Plain
+`x == y`
+
+## Code
+```
+{{c1::not a deletion}}
+```
+"#;
+
+    let output = Generator::new(&WorkTree, &Settings::default(), get_test_folder)
+        .generate_card_from_input(input, get_test_folder, None);
+    assert_eq!(output[0].kind, CardKind::Cloze);
     assert_eq!(
-        first.back,
-        r#"<p><img src="image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAQAAAAECAYAAACp8Z5+AAAAAXNSR0IB2cksfwAAAARnQU1BAACxjwv8YQUAAAAgY0hSTQAAeiYAAICEAAD6AAAAgOgAAHUwAADqYAAAOpgAABdwnLpRPAAAADhJREFUCNc1yDERgEAQBMGZLUIMoBkBnyMOA8TcEtFhe63zTrIDvDNs6DEtACpRn/4BpC0qANPyAYb0EsnPnK8eAAAAAElFTkSuQmCC" alt="title" /></p>"#
+        output[0].front,
+        "<h2>Capitals</h2>\n<p>The capital of France is {{c2::Paris}} and of Italy {{c1::Rome}}.\nAlso {{c3::Berlin}}.</p>"
+    );
+    assert_eq!(output[0].back, "");
+
+    assert_eq!(output[1].kind, CardKind::Cloze);
+    assert_eq!(
+        output[1].front,
+        "<h2>With extra</h2>\n<p><code>a == b</code> is {{c1::equality}}</p>"
+    );
+    assert_eq!(output[1].back, "<p>Comparison operator</p>");
+
+    assert_eq!(output[2].kind, CardKind::Basic);
+    assert_eq!(output[3].kind, CardKind::Basic);
+}
+
+#[rstest]
+#[case("---\nkind: reversed\n---\n")]
+#[case("+++\nkind = \"basic-and-reversed\"\n+++\n")]
+pub fn test_card_kind(get_test_folder: &Path, #[case] front_matter: &str) {
+    let input = format!(
+        "{front_matter}\n## Dog\nChien\n\n## Cat {{.basic}}\nChat\n\n## Bird {{#bird .optional-reversed}}\nOiseau\n"
+    );
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(&input, get_test_folder, None);
+    assert_eq!(output.len(), 3);
+    assert_eq!(output[0].kind, CardKind::BasicAndReversed);
+    assert_eq!(output[0].lines.0, front_matter.lines().count() + 2);
+    assert_eq!(output[1].kind, CardKind::Basic);
+    assert_eq!(output[1].front, "<h2>Cat</h2>");
+    assert_eq!(output[2].kind, CardKind::OptionalReversed);
+    assert_eq!(output[2].id, "bird");
+
+    let basic = generator.generate_card_from_input("## Dog\nChien\n", get_test_folder, None);
+    assert_eq!(basic[0].kind, CardKind::Basic);
+    assert_ne!(basic[0].hash, output[0].hash);
+
+    let settings = Settings {
+        kind: CardKind::OptionalReversed,
+        ..Default::default()
+    };
+    let repo_kind = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        "## Dog\nChien\n",
+        get_test_folder,
+        None,
+    );
+    assert_eq!(repo_kind[0].kind, CardKind::OptionalReversed);
+}
+
+#[rstest]
+pub fn test_invalid_front_matter(get_test_folder: &Path) {
+    let input = "---\nkind: [reversed\n---\n## Dog\nChien\n";
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, &get_test_folder.join("a.md"), None);
+    assert_eq!(output.len(), 1);
+    assert_eq!(output[0].kind, CardKind::Basic);
+
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].file, "a.md");
+    assert_eq!(diagnostics[0].line, 1);
+    assert!(diagnostics[0].message.contains("Invalid front matter"));
+}
+
+#[rstest]
+pub fn test_tags(get_test_folder: &Path) {
+    let input = "---\ntags: [biology, exam]\n---\n## What is a cell? #cells #exam {#cell}\nThe smallest unit\n\n## C# #lang\nA language\n";
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, get_test_folder, None);
+    assert_eq!(output[0].front, "<h2>What is a cell?</h2>");
+    assert_eq!(output[0].tags, vec!["biology", "cells", "exam"]);
+    assert_eq!(output[1].front, "<h2>C#</h2>");
+    assert_eq!(output[1].tags, vec!["biology", "exam", "lang"]);
+
+    let retagged = generator.generate_card_from_input(
+        &input.replace("[biology, exam]", "[biology]"),
+        get_test_folder,
+        None,
+    );
+    assert_eq!(retagged[1].id, output[1].id);
+    assert_ne!(retagged[1].hash, output[1].hash);
+}
+
+#[rstest]
+pub fn test_audio_and_video(get_test_folder: &Path) {
+    let input = "## Listen\n![](clip.mp3) or [again](clip.mp3)\n%\n![A clip](clip.mp4) [link](https://example.org) [picture](blahaj.png)\n";
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, &get_test_folder.join("test.md"), None);
+    let find = |ext: &str| {
+        output[0]
+            .media
+            .iter()
+            .find(|name| name.ends_with(ext))
+            .unwrap()
+            .clone()
+    };
+    let (sound, video) = (find(".mp3"), find(".mp4"));
+    assert_eq!(
+        output[0].front,
+        format!("<h2>Listen</h2>\n<p>[sound:{sound}] or [sound:{sound}]</p>")
+    );
+    assert_eq!(
+        output[0].back,
+        format!(
+            r#"<p><video controls src="{video}">A clip</video> <a href="https://example.org">link</a> <a href="blahaj.png">picture</a></p>"#
+        )
+    );
+    assert_eq!(generator.take_media().len(), 2);
+}
+
+#[rstest]
+pub fn test_svg_media(get_test_folder: &Path) {
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(
+        "## Diagram\n![](diagram.svg)\n",
+        &get_test_folder.join("test.md"),
+        None,
+    );
+    let name = &output[0].media[0];
+    assert!(name.ends_with(".svg"));
+    assert_eq!(
+        output[0].back,
+        format!(r#"<p><img src="{name}" alt="" /></p>"#)
+    );
+
+    let svg = String::from_utf8(generator.take_media().remove(name).unwrap()).unwrap();
+    assert_eq!(
+        svg,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+
+<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10">
+  
+  <a><rect width="10" height="10" fill="blue"/></a>
+  
+</svg>
+"#
+    );
+}
+
+#[rstest]
+pub fn test_media_outside_of_root(get_test_folder: &Path) {
+    let repo = tempfile::TempDir::new().unwrap();
+    let outside = tempfile::TempDir::new().unwrap();
+    let secret = outside.path().join("secret.png");
+    std::fs::copy(get_test_folder.join("blahaj.png"), &secret).unwrap();
+    std::os::unix::fs::symlink(&secret, repo.path().join("link.png")).unwrap();
+    let deck = repo.path().join("deck");
+    std::fs::create_dir(&deck).unwrap();
+
+    let relative = format!(
+        "../../{}/secret.png",
+        outside.path().file_name().unwrap().to_string_lossy()
+    );
+    let input = format!("## Secret\n![]({relative})\n\n## Link\n![](/link.png)\n");
+    let file = repo.path().join("deck/cards.md");
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, &deck).with_root(repo.path());
+    let output = generator.generate_card_from_input(&input, &file, None);
+    assert!(output.iter().all(|card| card.media.is_empty()));
+    assert!(generator.take_media().is_empty());
+
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].file, "deck/cards.md");
+    assert_eq!(diagnostics[0].line, 1);
+    assert_eq!(diagnostics[1].line, 4);
+    assert!(diagnostics[1].message.starts_with("/link.png is outside"));
+
+    let settings = Settings {
+        media_dirs: vec![outside.path().to_path_buf()],
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &settings, &deck).with_root(repo.path());
+    let output = generator.generate_card_from_input(&input, &file, None);
+    assert_eq!(output[0].media, output[1].media);
+    assert_eq!(generator.take_media().len(), 1);
+    assert!(generator.take_diagnostics().is_empty());
+}
+
+#[rstest]
+pub fn test_server_side_math(get_test_folder: &Path) {
+    let input = "## Sum\n$a^2$\n%\n$$\\frac{1}{2$$\n";
+    let client = Generator::new(&WorkTree, &Settings::default(), get_test_folder)
+        .generate_card_from_input(input, get_test_folder, None);
+
+    let settings = Settings {
+        math: MathMode::Mathml,
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, get_test_folder, None);
+    assert!(output[0].front.contains("<math"));
+    assert!(output[0].front.contains("<msup>"));
+    assert!(!output[0].front.contains("katex-html"));
+    assert_eq!(output[0].back, "<p>$$\\frac{1}{2$$</p>");
+    assert_ne!(output[0].hash, client[0].hash);
+
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].line, 1);
+    assert!(
+        diagnostics[0]
+            .message
+            .starts_with("Invalid math `\\frac{1}{2`")
+    );
+
+    let settings = Settings {
+        math: MathMode::Html,
+        ..Default::default()
+    };
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    assert!(output[0].front.contains("katex-html"));
+}
+
+#[rstest]
+pub fn test_mathjax_delimiters(get_test_folder: &Path) {
+    let input = "## Order\nIs $a < b$ & $b > c$?\n%\n- item\n\n  $$\nx &= 1\n$$\n\n- $$y$$\n";
+    let settings = Settings {
+        math: MathMode::Mathjax,
+        ..Default::default()
+    };
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    assert_eq!(
+        output[0].front,
+        r"<h2>Order</h2>
+<p>Is \(a &lt; b\) &amp; \(b &gt; c\)?</p>"
+    );
+    assert_eq!(
+        output[0].back,
+        r"<ul>
+<li>
+<p>item</p>
+<p>\[
+x &amp;= 1
+\]</p>
+</li>
+<li>
+<p>\[y\]</p>
+</li>
+</ul>"
+    );
+}
+
+#[rstest]
+pub fn test_macros_expansion() {
+    let macros = Macros::parse(
+        r"% Sets
+\newcommand{\R}{\mathbb{R}}
+\renewcommand\norm[1]{\lVert #1 \rVert}
+\newcommand{\seq}[2][n]{(#2_#1)}
+\def\pair#1#2{\langle #1, #2 \rangle}
+\DeclareMathOperator*{\argmax}{arg\,max}
+\newcommand{\normR}{\norm{\R}}",
+    )
+    .unwrap();
+
+    let mut used = BTreeSet::new();
+    assert_eq!(
+        macros
+            .expand(r"x \in \R y, \norm{x} + \seq{u} + \seq[k]{v}", &mut used)
+            .unwrap(),
+        r"x \in \mathbb{R} y, \lVert x \rVert + (u_n) + (v_k)"
+    );
+    assert_eq!(
+        macros
+            .expand(r"\pair a{b} = \argmax_x f \normR", &mut used)
+            .unwrap(),
+        r"\langle a, b \rangle = \operatorname*{arg\,max}_x f \lVert \mathbb{R}\rVert"
+    );
+    assert_eq!(
+        used.into_iter().collect::<Vec<_>>(),
+        ["R", "argmax", "norm", "normR", "pair", "seq"]
+    );
+    assert!(Macros::parse(r"\newcommand{\broken}[1]").is_err());
+}
+
+#[rstest]
+pub fn test_macros_expansion_is_bounded(get_test_folder: &Path) {
+    let macros = Macros::parse(r"\def\x{\x\x}").unwrap();
+    let error = macros.expand(r"\x", &mut BTreeSet::new()).unwrap_err();
+    assert!(error.to_string().contains(r"\x may be recursive"));
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("macros.tex"), r"\def\x{\x\x}").unwrap();
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder).with_root(dir.path());
+    let cards = generator.generate_card_from_input(
+        "## Doubling\n$\\x$",
+        &get_test_folder.join("test.md"),
+        None,
+    );
+    assert_eq!(cards[0].back, r"<p>$\x$</p>");
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("may be recursive"));
+}
+
+#[rstest]
+pub fn test_macros_in_cards(get_test_folder: &Path) {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("defs.tex"), r"\newcommand{\R}{\mathbb{R}}").unwrap();
+    let input = "## Reals\n$x \\in \\R$\n\n## Plain\n$x$";
+    let settings = Settings {
+        macros: Some("defs.tex".into()),
+        ..Default::default()
+    };
+    let generate = || {
+        Generator::new(&WorkTree, &settings, get_test_folder)
+            .with_root(dir.path())
+            .generate_card_from_input(input, &get_test_folder.join("test.md"), None)
+    };
+
+    let before = generate();
+    assert_eq!(before[0].back, r"<p>$x \in \mathbb{R}$</p>");
+    std::fs::write(dir.path().join("defs.tex"), r"\newcommand{\R}{\mathbf{R}}").unwrap();
+    let after = generate();
+    assert_eq!(after[0].back, r"<p>$x \in \mathbf{R}$</p>");
+    assert_ne!(before[0].hash, after[0].hash);
+    assert_eq!(before[1].hash, after[1].hash);
+
+    let missing = Settings {
+        macros: Some("missing.tex".into()),
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &missing, get_test_folder);
+    generator.generate_card_from_input(input, &get_test_folder.join("test.md"), None);
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].file, "missing.tex");
+}
+
+#[rstest]
+pub fn test_code_highlighting(get_test_folder: &Path) {
+    assert_eq!(
+        CodeInfo::parse("rust numbered {3, 5-6}", 6),
+        CodeInfo {
+            language: "rust".to_string(),
+            lines: BTreeSet::from([3, 5, 6]),
+            numbered: true,
+            invalid: Vec::new(),
+        }
+    );
+    assert_eq!(
+        CodeInfo::parse("rust {0-2, 3-999999999, 5-4, x}", 4),
+        CodeInfo {
+            language: "rust".to_string(),
+            lines: BTreeSet::from([1, 2, 3, 4]),
+            numbered: false,
+            invalid: vec![
+                "0-2".to_string(),
+                "3-999999999".to_string(),
+                "5-4".to_string(),
+                "x".to_string()
+            ],
+        }
+    );
+
+    let input = "## Code\n```rust {2}\nfn main() {\n    let x = 1;\n}\n```\n\n```\nplain\n```";
+    let settings = Settings::default();
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    let back = &output[0].back;
+    assert!(back.starts_with("<pre style=\"background-color:#ffffffff;"));
+    assert!(back.contains("<code class=\"language-rust\">"));
+    assert!(back.contains("<span style=\"display:block;background-color:"));
+    assert!(back.contains("<span style=\"font-weight:bold;color:#a71d5d;\">fn </span>"));
+    assert!(back.ends_with("<pre><code>plain\n</code></pre>"));
+
+    let settings = Settings {
+        code_theme: "none".to_string(),
+        ..Default::default()
+    };
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    assert!(
+        output[0]
+            .back
+            .starts_with("<pre><code class=\"language-rust\">fn main")
+    );
+
+    let settings = Settings {
+        code_theme: "Missing".to_string(),
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    generator.generate_card_from_input(input, &get_test_folder.join("test.md"), None);
+    generator.generate_card_from_input(input, &get_test_folder.join("other.md"), None);
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(
+        diagnostics[0]
+            .message
+            .starts_with("Unknown code theme Missing")
+    );
+
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    generator.generate_card_from_input(
+        "## Code\n```rust {1-999999999}\nfn main() {}\n```",
+        &get_test_folder.join("test.md"),
+        None,
     );
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "Invalid line range {1-999999999}, the code block ends at line 1"
+    );
+}
+
+#[rstest]
+pub fn test_footnotes_at_end_of_file(get_test_folder: &Path) {
+    let input = "## First\nA claim[^source]\n\n## Second\nNothing\n\n## Third\nAgain[^source]\n\n[^source]: A book\n";
+    let settings = Settings::default();
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    assert_eq!(output.len(), 3);
+    assert!(output[0].back.contains("<section class=\"footnotes\""));
+    assert!(output[0].back.contains("A book"));
+    assert_eq!(output[0].lines, (1, 2));
+    assert!(!output[1].back.contains("A book"));
+    assert!(output[2].back.contains("A book"));
+}
+
+#[rstest]
+pub fn test_gfm_extensions(get_test_folder: &Path) {
+    let input = "## GFM\n| a | b |\n|---|---|\n| 1 | 2 |\n\n~~old~~ see https://example.org and[^1]\n\n- [x] done\n\n[^1]: Note";
+    let settings = Settings::default();
+    let output = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    let back = &output[0].back;
+    assert!(back.starts_with("<table>"));
+    assert!(back.contains("<del>old</del>"));
+    assert!(back.contains("<a href=\"https://example.org\">https://example.org</a>"));
+    assert!(back.contains("<input type=\"checkbox\" checked=\"\" disabled=\"\" /> done"));
+    assert!(back.contains("<section class=\"footnotes\""));
+
+    let settings = Settings {
+        extensions: Extensions {
+            table: false,
+            autolink: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let without = Generator::new(&WorkTree, &settings, get_test_folder).generate_card_from_input(
+        input,
+        get_test_folder,
+        None,
+    );
+    assert!(without[0].back.starts_with("<p>| a | b |"));
+    assert!(without[0].back.contains("see https://example.org and<sup"));
+    assert_ne!(output[0].hash, without[0].hash);
+}
+
+#[rstest]
+pub fn test_diagrams(get_test_folder: &Path) {
+    let input = "## Flow\n```dot\ndigraph { a -> b }\n```\n\n```mermaid\ngraph TD; A-->B\n```";
+    let settings = Settings::default();
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, &get_test_folder.join("test.md"), None);
+    let media = generator.take_media();
+    let (name, svg) = media.iter().next().unwrap();
+    assert!(name.ends_with(".svg"));
+    assert!(String::from_utf8_lossy(svg).contains("<svg"));
+    assert_eq!(output[0].media, vec![name.clone()]);
+    assert!(output[0].back.starts_with(&format!(
+        "<p><img src=\"{name}\" alt=\"dot diagram\" /></p>"
+    )));
+    assert!(
+        output[0]
+            .back
+            .ends_with("<code class=\"language-mermaid\">graph TD; A--&gt;B\n</code></pre>")
+    );
+    // Left as code without a command, the default, instead of a warning at each sync
+    assert!(generator.take_diagnostics().is_empty());
+
+    let settings = Settings {
+        diagrams: Diagrams {
+            mermaid: [
+                "sh",
+                "-c",
+                "cat > /dev/null; echo '<svg><script>x</script><text>B</text></svg>'",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(input, &get_test_folder.join("test.md"), None);
+    assert_eq!(output[0].media.len(), 2);
+    assert!(generator.take_diagnostics().is_empty());
+    assert!(
+        generator
+            .take_media()
+            .values()
+            .any(|svg| svg.as_slice() == b"<svg><text>B</text></svg>\n")
+    );
+}
+
+#[rstest]
+#[case::layout_panic(
+    "digraph { a [shape=record, label=\"\"] }",
+    Vec::new(),
+    "layout-rs cannot lay out the graph"
+)]
+#[case::timeout(
+    "digraph { a }",
+    vec!["sh", "-c", "exec sleep 5"],
+    "sh did not finish within 1 seconds"
+)]
+#[case::timeout_with_children(
+    "digraph { a }",
+    vec!["sh", "-c", "sleep 30; true"],
+    "sh did not finish within 1 seconds"
+)]
+pub fn test_diagram_failures(
+    get_test_folder: &Path,
+    #[case] source: &str,
+    #[case] dot: Vec<&str>,
+    #[case] message: &str,
+) {
+    let input = format!("## Graph\n```dot\n{source}\n```\n");
+    let settings = Settings {
+        diagrams: Diagrams {
+            dot: dot.into_iter().map(String::from).collect(),
+            timeout: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(&input, &get_test_folder.join("test.md"), None);
+    assert!(output[0].media.is_empty());
+    let diagnostics = generator.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.ends_with(message));
+}
+
+#[rstest]
+pub fn test_diagram_command_output_before_input(get_test_folder: &Path) {
+    // Fills the output pipe before reading a source larger than the input pipe
+    let padding = "a".repeat(200_000);
+    let input = format!("## Graph\n```dot\ndigraph {{ {padding} }}\n```\n");
+    let settings = Settings {
+        diagrams: Diagrams {
+            dot: [
+                "sh",
+                "-c",
+                "echo '<svg>'; head -c 200000 /dev/zero | tr '\\0' ' '; cat > /dev/null; echo '</svg>'",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let generator = Generator::new(&WorkTree, &settings, get_test_folder);
+    let output = generator.generate_card_from_input(&input, &get_test_folder.join("test.md"), None);
+    assert!(generator.take_diagnostics().is_empty());
+    assert_eq!(output[0].media.len(), 1);
 }
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use rstest::rstest;
+
+use crate::{
+    config::Settings,
+    data::{Card, Output},
+    init::Init,
+    inventory::{Inventory, reconcile},
+    with_guids,
+};
+
+fn generate_root(root: &str) -> Output {
+    let settings = Settings::default();
+    let decks = Init::new("", "", std::path::Path::new("./tests/test"), &settings)
+        .generate()
+        .unwrap();
+    with_guids(decks, "namespace")
+        .into_iter()
+        .map(|(deck, cards)| (format!("{root}::{deck}"), cards))
+        .collect()
+}
+
+#[rstest]
+pub fn test_reconcile() {
+    let decks = generate_root("root");
+    let kept = decks.get("root::a").unwrap().added[0].hash.clone();
+
+    let inventory = Inventory::from([
+        (
+            "root::a".to_string(),
+            HashMap::from([
+                ("random".to_string(), kept),
+                ("stale".to_string(), "stale".to_string()),
+            ]),
+        ),
+        (
+            "root::removed".to_string(),
+            HashMap::from([("gone".to_string(), "gone".to_string())]),
+        ),
+        (
+            "other::a".to_string(),
+            HashMap::from([("untouched".to_string(), "untouched".to_string())]),
+        ),
+    ]);
+
+    let output = reconcile(decks, &inventory, "root");
+
+    let a = output.get("root::a").unwrap();
+    assert!(a.added.is_empty());
+    assert_eq!(a.deleted, vec!["stale".to_string()]);
+
+    assert_eq!(output.get("root::a::b").unwrap().added.len(), 1);
+    assert_eq!(output.get("root::a::b::c").unwrap().added.len(), 1);
+
+    let removed = output.get("root::removed").unwrap();
+    assert!(removed.added.is_empty());
+    assert_eq!(removed.deleted, vec!["gone".to_string()]);
+
+    assert!(!output.contains_key("other::a"));
+}
+
+#[rstest]
+pub fn test_reconcile_edited_card() {
+    let decks = generate_root("root");
+    let card = decks.get("root::a").unwrap().added[0].clone();
+
+    let inventory = Inventory::from([(
+        "root::a".to_string(),
+        HashMap::from([(card.guid.clone(), "before".to_string())]),
+    )]);
+
+    let output = reconcile(decks, &inventory, "root");
+
+    let a = output.get("root::a").unwrap();
+    assert!(a.added.is_empty());
+    assert!(a.deleted.is_empty());
+    assert_eq!(a.updated.len(), 1);
+    assert_eq!(a.updated[0].old_hash, "before");
+    assert_eq!(a.updated[0].card.hash, card.hash);
+}
+
+#[rstest]
+pub fn test_guids_are_unique_across_decks() {
+    let guid = Card::guid_for("namespace", "a", "same");
+    assert_eq!(guid, Card::guid_for("namespace", "a", "same"));
+    assert_ne!(guid, Card::guid_for("namespace", "b", "same"));
+    assert_ne!(guid, Card::guid_for("other", "a", "same"));
+
+    let decks = generate_root("root");
+    let card = &decks.get("root::a").unwrap().added[0];
+    assert_eq!(card.guid, Card::guid_for("namespace", "a", &card.id));
+}
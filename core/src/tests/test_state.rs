@@ -0,0 +1,80 @@
+use rstest::rstest;
+use tempfile::TempDir;
+
+use crate::{config::Settings, state::SyncState, tests::TestRepo, updater::Updater};
+
+#[rstest]
+pub fn test_state_roundtrip() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("abcdef.state.toml");
+    assert!(SyncState::load(&path).unwrap().last_commit.is_none());
+
+    SyncState {
+        last_commit: Some("55974ad".to_string()),
+    }
+    .save(&path)
+    .unwrap();
+    assert_eq!(
+        SyncState::load(&path).unwrap().last_commit.as_deref(),
+        Some("55974ad")
+    );
+}
+
+#[rstest]
+pub fn test_sync_after_manual_pull() {
+    let upstream = TestRepo::new();
+    upstream.write("deck/a.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    let clone = upstream.clone_repo();
+
+    upstream.write("deck/b.md", "## B\nSecond\n");
+    upstream.commit("second");
+    upstream.write("deck/c.md", "## C\nThird\n");
+    let third = upstream.commit("third");
+    clone.git(&["pull", "--quiet"]);
+
+    let mut state = SyncState {
+        last_commit: Some(first),
+    };
+    let decks = Updater::new(clone.path(), Settings::default())
+        .generate(&mut state)
+        .unwrap();
+    assert_eq!(decks.get("deck").unwrap().added.len(), 2);
+    assert_eq!(state.last_commit.as_deref(), Some(third.as_str()));
+
+    let decks = Updater::new(clone.path(), Settings::default())
+        .generate(&mut state)
+        .unwrap();
+    assert!(decks.is_empty());
+}
+
+#[rstest]
+pub fn test_pending_state_waits_for_commit() {
+    let dir = TempDir::new().unwrap();
+    let slug = dir.path().join("abcdef");
+    let slug = slug.to_str().unwrap();
+    let path = SyncState::path_for(slug);
+    SyncState {
+        last_commit: Some("55974ad".to_string()),
+    }
+    .save(&path)
+    .unwrap();
+
+    SyncState {
+        last_commit: Some("8c1f0e2".to_string()),
+    }
+    .save(&SyncState::pending_path_for(slug))
+    .unwrap();
+    assert_eq!(
+        SyncState::load(&path).unwrap().last_commit.as_deref(),
+        Some("55974ad")
+    );
+
+    SyncState::commit(slug).unwrap();
+    assert_eq!(
+        SyncState::load(&path).unwrap().last_commit.as_deref(),
+        Some("8c1f0e2")
+    );
+    assert!(!SyncState::pending_path_for(slug).exists());
+    SyncState::commit(slug).unwrap();
+}
@@ -1,3 +1,4 @@
+use crate::config::Settings;
 use crate::init::Init;
 use rstest::rstest;
 use std::collections::HashSet;
@@ -5,7 +6,8 @@ use std::collections::HashSet;
 #[rstest]
 pub fn test() {
     let path = std::path::Path::new("./tests/test");
-    let init = Init::new("", "", path);
+    let settings = Settings::default();
+    let init = Init::new("", "", path, &settings);
     let set: HashSet<String> = HashSet::from_iter(
         init.get_subdecks_path()
             .unwrap()
@@ -20,7 +22,8 @@ pub fn test() {
 #[rstest]
 pub fn test_init() {
     let path = std::path::Path::new("./tests/test");
-    let init = Init::new("", "", path);
+    let settings = Settings::default();
+    let init = Init::new("", "", path, &settings);
     let generated = init.generate().unwrap();
 
     assert_eq!(generated.get("a::b").unwrap().added.len(), 1);
@@ -41,3 +44,20 @@ pub fn test_init() {
         "d346aae91becee16016fe6d97d5d34f9e50f1261230577302f467fc4398cb90a"
     );
 }
+
+#[rstest]
+pub fn test_folder_tags() {
+    let path = std::path::Path::new("./tests/test");
+    let settings = Settings {
+        folder_tags: true,
+        ..Default::default()
+    };
+    let generated = Init::new("", "", path, &settings).generate().unwrap();
+
+    let card = &generated.get("a::b::c").unwrap().added[0];
+    assert_eq!(card.tags, vec!["a::b::c"]);
+    assert_ne!(
+        card.hash,
+        "d9a617e1bab933800c1df24cbe1a6cc543b1e0dcfd414346f3c2d7a07647d0c2"
+    );
+}
@@ -0,0 +1,40 @@
+use rstest::rstest;
+
+use crate::svg::sanitize;
+
+#[rstest]
+#[case::escaped_url(
+    r#"<svg><a href="&#106;avascript:alert(1)"><rect/></a></svg>"#,
+    "<svg><a><rect/></a></svg>"
+)]
+#[case::named_entity(
+    r#"<svg><a href="java&#x09;script&colon;alert(1)"><rect/></a></svg>"#,
+    "<svg><a><rect/></a></svg>"
+)]
+#[case::foreign_object(
+    r#"<svg><foreignObject><iframe src="https://example.org"/></foreignObject><rect/></svg>"#,
+    "<svg><rect/></svg>"
+)]
+#[case::animate_href(
+    r#"<svg><a><animate attributeName="href" values="javascript:alert(1)"/><rect/></a></svg>"#,
+    "<svg><a><rect/></a></svg>"
+)]
+#[case::set_xlink_href(
+    r#"<svg><a><set attributeName="xlink:href" to="javascript:alert(1)"></set><rect/></a></svg>"#,
+    "<svg><a><rect/></a></svg>"
+)]
+#[case::external_use(
+    r#"<svg><use href="https://example.org/sprite.svg#icon"/><rect/></svg>"#,
+    "<svg><rect/></svg>"
+)]
+pub fn test_sanitize_bypass(#[case] input: &str, #[case] expected: &str) {
+    let output = sanitize(input.as_bytes()).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+#[rstest]
+pub fn test_sanitize_keeps_safe_content() {
+    let input = r##"<svg><rect id="r" fill="blue"/><use href="#r"/><animate attributeName="opacity" to="0"/><a href="https://example.org">&amp;</a></svg>"##;
+    let output = sanitize(input.as_bytes()).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), input);
+}
@@ -1,4 +1,13 @@
-use crate::{git::Git, tests::FakeRepo, updater::Updater};
+use crate::{
+    config::{Auth, GitBackend, Settings},
+    data::DeckEvent,
+    git::{Change, Git, GitError, Pin, SYNC_REF},
+    init::Init,
+    source::{GitTree, Source},
+    state::SyncState,
+    tests::{FakeRepo, TestRepo},
+    updater::Updater,
+};
 use rstest::{fixture, rstest};
 
 #[fixture]
@@ -9,16 +18,20 @@ pub fn get_fake_repo() -> FakeRepo {
 #[rstest]
 pub fn test_folder_diff(get_fake_repo: FakeRepo) {
     let repo_path = get_fake_repo.0.path().to_str().unwrap().to_string();
-    let diff = Git::new(repo_path).diff("55974ad", "d60481d").unwrap();
-    let folders = Updater::get_folder_with_diff(&diff).unwrap();
+    let diff = Git::open(repo_path, GitBackend::default())
+        .diff("55974ad", "d60481d")
+        .unwrap();
+    let folders = Updater::get_folder_with_diff(&diff);
     assert!(folders.contains("basic_cards"));
 }
 
 #[rstest]
 pub fn test_generation(get_fake_repo: FakeRepo) {
     let repo_path = get_fake_repo.0.path().to_str().unwrap().to_string();
-    let a = Updater::new(repo_path.clone());
-    let diff = Git::new(repo_path).diff("55974ad", "d60481d").unwrap();
+    let a = Updater::new(repo_path.clone(), Settings::default());
+    let diff = Git::open(repo_path, GitBackend::default())
+        .diff("55974ad", "d60481d")
+        .unwrap();
     let decks = a
         .generate_decks_from_diff(&diff, "55974ad", "d60481d")
         .unwrap();
@@ -31,16 +44,20 @@ pub fn test_generation(get_fake_repo: FakeRepo) {
 #[rstest]
 pub fn test_new_subdecks_folder_diff(get_fake_repo: FakeRepo) {
     let repo_path = get_fake_repo.0.path().to_str().unwrap().to_string();
-    let diff = Git::new(repo_path).diff("d60481d", "54012ee").unwrap();
-    let folders = Updater::get_folder_with_diff(&diff).unwrap();
+    let diff = Git::open(repo_path, GitBackend::default())
+        .diff("d60481d", "54012ee")
+        .unwrap();
+    let folders = Updater::get_folder_with_diff(&diff);
     assert!(folders.contains("basic_cards/subdecks"));
 }
 
 #[rstest]
 pub fn test_new_subdecks_diff_output(get_fake_repo: FakeRepo) {
     let repo_path = get_fake_repo.0.path().to_str().unwrap().to_string();
-    let a = Updater::new(repo_path.clone());
-    let diff = Git::new(repo_path).diff("d60481d", "54012ee").unwrap();
+    let a = Updater::new(repo_path.clone(), Settings::default());
+    let diff = Git::open(repo_path, GitBackend::default())
+        .diff("d60481d", "54012ee")
+        .unwrap();
     let g = a
         .generate_decks_from_diff(&diff, "d60481d", "54012ee")
         .unwrap();
@@ -52,3 +69,588 @@ pub fn test_new_subdecks_diff_output(get_fake_repo: FakeRepo) {
         "87588180b9688dab251cccca1ab23c377ea998e21a158e250772a5b770b1e098"
     );
 }
+
+#[rstest]
+pub fn test_edited_card_is_updated(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let repo = TestRepo::new();
+    repo.write(
+        "deck/cards.md",
+        "## Question\nAnswr\n\n## Other {#other}\nFirst",
+    );
+    let from = repo.commit("init");
+    repo.write(
+        "deck/cards.md",
+        "## Question\nAnswer\n\n## Renamed {#other}\nFirst",
+    );
+    let to = repo.commit("fix typo");
+
+    let diff = Git::open(repo.path(), backend).diff(&from, &to).unwrap();
+    let decks = Updater::new(
+        repo.path(),
+        Settings {
+            git: backend,
+            ..Default::default()
+        },
+    )
+    .generate_decks_from_diff(&diff, &from, &to)
+    .unwrap();
+    let deck = decks.get("deck").unwrap();
+
+    assert!(deck.added.is_empty());
+    assert!(deck.deleted.is_empty());
+    assert_eq!(deck.updated.len(), 2);
+    let other = deck.updated.iter().find(|f| f.card.id == "other").unwrap();
+    assert_eq!(other.card.front, "<h2>Renamed</h2>");
+}
+
+#[rstest]
+pub fn test_image_edit_updates_cards(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let repo = TestRepo::new();
+    let image = std::fs::read("./tests/blahaj.png").unwrap();
+    let image_path = std::path::Path::new(&repo.path()).join("assets/shark.png");
+    std::fs::create_dir_all(image_path.parent().unwrap()).unwrap();
+    std::fs::write(&image_path, &image).unwrap();
+    repo.write("deck/cards.md", "## Shark\n![](../assets/shark.png)\n");
+    repo.write("other/cards.md", "## Whale\nNo picture\n");
+    let from = repo.commit("init");
+    std::fs::write(&image_path, [image.as_slice(), b"edited"].concat()).unwrap();
+    let to = repo.commit("edit the picture");
+
+    let diff = Git::open(repo.path(), backend).diff(&from, &to).unwrap();
+    let decks = Updater::new(
+        repo.path(),
+        Settings {
+            git: backend,
+            ..Default::default()
+        },
+    )
+    .generate_decks_from_diff(&diff, &from, &to)
+    .unwrap();
+
+    let deck = decks.get("deck").unwrap();
+    assert!(deck.added.is_empty());
+    assert!(deck.deleted.is_empty());
+    assert_eq!(deck.updated.len(), 1);
+    let media = &deck.updated[0].card.media;
+    assert_eq!(media.len(), 1);
+    assert!(deck.media.contains_key(&media[0]));
+    assert!(
+        decks
+            .get("other")
+            .is_none_or(|other| other.updated.is_empty())
+    );
+}
+
+#[rstest]
+pub fn test_target_names_decks_as_init() {
+    let repo = TestRepo::new();
+    repo.write("cards/deck/cards.md", "## A\nFirst\n");
+    repo.write("notes/deck/cards.md", "## B\nOutside\n");
+    let from = repo.commit("init");
+    repo.write("cards/deck/cards.md", "## A\nSecond\n");
+    repo.write("notes/deck/cards.md", "## B\nStill outside\n");
+    let to = repo.commit("edit");
+
+    let target = std::path::Path::new(&repo.path()).join("cards");
+    let settings = Settings::default();
+    let git = Git::open(repo.path(), settings.git);
+    let generated = Init::new("", &repo.path(), &target, &settings)
+        .generate_at(&git, &to)
+        .unwrap();
+    let diff = git.diff(&from, &to).unwrap();
+    let decks = Updater::new(repo.path(), settings.clone())
+        .with_target(&target)
+        .generate_decks_from_diff(&diff, &from, &to)
+        .unwrap();
+
+    assert_eq!(generated.keys().collect::<Vec<_>>(), ["deck"]);
+    assert_eq!(decks.keys().collect::<Vec<_>>(), ["deck"]);
+    assert_eq!(
+        decks["deck"].updated[0].card.hash,
+        generated["deck"].added[0].hash
+    );
+}
+
+#[rstest]
+pub fn test_macros_edit_updates_cards(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let repo = TestRepo::new();
+    repo.write("macros.tex", "\\newcommand{\\R}{\\mathbb{R}}\n");
+    repo.write("deck/cards.md", "## Reals\n$\\R$\n\n## Plain\n$x$");
+    repo.write("other/cards.md", "## Other\nNo math");
+    let from = repo.commit("init");
+    repo.write("macros.tex", "\\newcommand{\\R}{\\mathbf{R}}\n");
+    let to = repo.commit("bold reals");
+
+    let diff = Git::open(repo.path(), backend).diff(&from, &to).unwrap();
+    let decks = Updater::new(
+        repo.path(),
+        Settings {
+            git: backend,
+            ..Default::default()
+        },
+    )
+    .generate_decks_from_diff(&diff, &from, &to)
+    .unwrap();
+
+    let deck = decks.get("deck").unwrap();
+    assert_eq!(deck.updated.len(), 1);
+    assert_eq!(deck.updated[0].card.back, r"<p>$\mathbf{R}$</p>");
+    assert!(decks.get("other").unwrap().updated.is_empty());
+}
+
+#[rstest]
+pub fn test_diff_keeps_worktree(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let repo = TestRepo::new();
+    repo.write("deck/cards.md", "## Question\nAnswer\n");
+    let from = repo.commit("init");
+    repo.write("deck/cards.md", "## Question\nAnswer\n\n## New\nCard\n");
+    let to = repo.commit("add card");
+    repo.write("deck/cards.md", "## Local edit\nNot committed");
+
+    let diff = Git::open(repo.path(), backend).diff(&from, &to).unwrap();
+    let decks = Updater::new(
+        repo.path(),
+        Settings {
+            git: backend,
+            ..Default::default()
+        },
+    )
+    .generate_decks_from_diff(&diff, &from, &to)
+    .unwrap();
+
+    assert_eq!(decks.get("deck").unwrap().added.len(), 1);
+    assert_eq!(repo.git(&["rev-parse", "--abbrev-ref", "HEAD"]), "main");
+    assert_eq!(
+        std::fs::read_to_string(format!("{}/deck/cards.md", repo.path())).unwrap(),
+        "## Local edit\nNot committed"
+    );
+}
+
+#[rstest]
+pub fn test_git_tree_source(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let repo = TestRepo::new();
+    repo.write("deck/cards.md", "## Question\nAnswer");
+    repo.write("deck/image.png", "not an image");
+    let commit = repo.commit("init");
+    repo.write("deck/cards.md", "## Changed");
+
+    let git = Git::open(repo.path(), backend);
+    let source = GitTree::new(&git, &commit);
+    let deck = std::path::Path::new(&repo.path()).join("deck");
+    let mut files = source.files(&deck);
+    files.sort();
+    assert_eq!(files, vec![deck.join("cards.md"), deck.join("image.png")]);
+    assert_eq!(
+        source.read_to_string(&deck.join("cards.md")).unwrap(),
+        "## Question\nAnswer"
+    );
+    assert!(source.read(&deck.join("../../outside.md")).is_err());
+    assert_eq!(source.canonicalize(&deck.join("../../outside.md")), None);
+    assert_eq!(
+        source.canonicalize(&deck.join("../deck/image.png")),
+        Some(std::path::Path::new(&repo.path()).join("deck/image.png"))
+    );
+}
+
+#[rstest]
+pub fn test_deck_lifecycle(#[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend) {
+    let repo = TestRepo::new();
+    repo.write("old/cards.md", "## A\nFirst\n\n## B\nSecond\n");
+    repo.write("emptied/cards.md", "## C\nThird\n");
+    repo.write("emptied/image.png", "image\n");
+    repo.write("deleted/cards.md", "## D\nFourth\n");
+    let from = repo.commit("init");
+
+    repo.git(&["mv", "old", "new"]);
+    repo.write("new/cards.md", "## A\nFirst\n\n## B\nSecond, edited\n");
+    repo.git(&["rm", "--quiet", "emptied/cards.md"]);
+    repo.git(&["rm", "--quiet", "-r", "deleted"]);
+    repo.write("created/cards.md", "## E\nFifth\n");
+    repo.write("README.md", "## Not a card\n");
+    let to = repo.commit("reorganize");
+
+    let diff = Git::open(repo.path(), backend).diff(&from, &to).unwrap();
+    let decks = Updater::new(
+        repo.path(),
+        Settings {
+            git: backend,
+            ..Default::default()
+        },
+    )
+    .generate_decks_from_diff(&diff, &from, &to)
+    .unwrap();
+
+    let new = decks.get("new").unwrap();
+    assert_eq!(new.event, Some(DeckEvent::Renamed));
+    assert_eq!(new.renamed_from.as_deref(), Some("old"));
+    assert!(new.added.is_empty());
+    assert_eq!(new.updated.len(), 1);
+    assert!(!decks.contains_key("old"));
+
+    let emptied = decks.get("emptied").unwrap();
+    assert_eq!(emptied.event, Some(DeckEvent::Emptied));
+    assert_eq!(emptied.deleted.len(), 1);
+
+    let deleted = decks.get("deleted").unwrap();
+    assert_eq!(deleted.event, Some(DeckEvent::Deleted));
+    assert_eq!(deleted.deleted.len(), 1);
+
+    let created = decks.get("created").unwrap();
+    assert_eq!(created.event, Some(DeckEvent::Created));
+    assert_eq!(created.added.len(), 1);
+
+    assert!(!decks.contains_key(""));
+}
+
+#[rstest]
+pub fn test_git_backends(#[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("clone");
+    let url = format!("file://{}", upstream.path());
+    let git = Git::clone(
+        &url,
+        path.to_str().unwrap(),
+        backend,
+        &Auth::default(),
+        &Pin::Default,
+    )
+    .unwrap();
+    assert_eq!(git.rev_parse("HEAD").unwrap(), first);
+    assert!(matches!(
+        git.rev_parse("missing"),
+        Err(GitError::UnknownRevision(_))
+    ));
+
+    let update = git.update(&Pin::Default).unwrap();
+    assert_eq!(update.from_commit, update.to_commit);
+
+    upstream.write("deck/cards.md", "## A\nEdited\n");
+    upstream.write("other/cards.md", "## B\nSecond\n");
+    let second = upstream.commit("second");
+    let update = git.update(&Pin::Default).unwrap();
+    assert_eq!(
+        (update.from_commit, update.to_commit),
+        (first.clone(), second.clone())
+    );
+    assert_eq!(
+        std::fs::read_to_string(path.join("deck/cards.md")).unwrap(),
+        "## A\nEdited\n"
+    );
+
+    let mut changes = git.diff(&first, &second).unwrap();
+    changes.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+    assert_eq!(
+        changes,
+        vec![
+            Change {
+                old_path: Some("deck/cards.md".into()),
+                new_path: Some("deck/cards.md".into()),
+            },
+            Change {
+                old_path: None,
+                new_path: Some("other/cards.md".into()),
+            },
+        ]
+    );
+    let mut dirs = git.ls_dirs(&second).unwrap();
+    dirs.sort();
+    assert_eq!(dirs, ["deck", "other"]);
+    assert!(matches!(
+        git.cat_file(&first, std::path::Path::new("other/cards.md")),
+        Err(GitError::NotFound { .. })
+    ));
+}
+
+#[rstest]
+pub fn test_update_follows_force_push(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    let clone = upstream.clone_repo();
+    upstream.write("deck/cards.md", "## A\nRewritten\n");
+    upstream.git(&["commit", "--quiet", "--amend", "--all", "-m", "rewritten"]);
+    let rewritten = upstream.git(&["rev-parse", "HEAD"]);
+
+    let update = Git::open(clone.path(), backend)
+        .update(&Pin::Default)
+        .unwrap();
+    assert_eq!(
+        (update.from_commit, update.to_commit),
+        (first.clone(), rewritten.clone())
+    );
+    assert!(update.local_changes.is_empty());
+    assert_eq!(clone.git(&["rev-parse", SYNC_REF]), rewritten);
+    assert_eq!(clone.git(&["rev-parse", "HEAD"]), first);
+    assert_eq!(clone.git(&["rev-list", "--merges", "--count", "HEAD"]), "0");
+}
+
+#[rstest]
+pub fn test_local_edits_are_reported(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    let clone = upstream.clone_repo();
+    clone.write("deck/cards.md", "## A\nLocal edit\n");
+    upstream.write("deck/cards.md", "## A\nRemote edit\n");
+    let second = upstream.commit("remote");
+
+    let mut state = SyncState {
+        last_commit: Some(first.clone()),
+    };
+    let settings = Settings {
+        git: backend,
+        ..Default::default()
+    };
+    let decks = Updater::new(clone.path(), settings.clone())
+        .generate(&mut state)
+        .unwrap();
+    let deck = decks.get("deck").unwrap();
+    assert_eq!(deck.updated[0].card.back, "<p>Remote edit</p>");
+    let path = clone.path();
+    let init = Init::new("", &path, std::path::Path::new(&path), &settings);
+    let decks = init
+        .generate_at(&Git::open(clone.path(), backend), &second)
+        .unwrap();
+    assert_eq!(decks["deck"].added[0].back, "<p>Remote edit</p>");
+    assert_eq!(deck.diagnostics.len(), 1);
+    assert_eq!(deck.diagnostics[0].file, "deck/cards.md");
+    assert_eq!(state.last_commit, Some(second));
+    assert_eq!(clone.git(&["rev-parse", "HEAD"]), first);
+    assert_eq!(
+        std::fs::read_to_string(format!("{}/deck/cards.md", clone.path())).unwrap(),
+        "## A\nLocal edit\n"
+    );
+}
+
+#[rstest]
+pub fn test_pin_changes_move_the_clone(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    upstream.git(&["tag", "v1"]);
+    upstream.write("deck/cards.md", "## A\nStable\n");
+    let stable = upstream.commit("stable");
+    upstream.git(&["branch", "stable"]);
+    upstream.write("deck/cards.md", "## A\nUnreleased\n");
+    upstream.commit("unreleased");
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("clone");
+    let url = format!("file://{}", upstream.path());
+    let git = Git::clone(
+        &url,
+        path.to_str().unwrap(),
+        backend,
+        &Auth::default(),
+        &Pin::Tag("v1".to_string()),
+    )
+    .unwrap();
+    assert_eq!(git.rev_parse("HEAD").unwrap(), first);
+    assert_eq!(git.rev_parse(SYNC_REF).unwrap(), first);
+
+    let mut state = SyncState {
+        last_commit: Some(first.clone()),
+    };
+    let settings = Settings {
+        git: backend,
+        ..Default::default()
+    };
+    let decks = Updater::new(path.to_str().unwrap().to_string(), settings.clone())
+        .with_pin(Pin::Branch("stable".to_string()))
+        .generate(&mut state)
+        .unwrap();
+    assert_eq!(decks["deck"].updated[0].card.back, "<p>Stable</p>");
+    assert_eq!(state.last_commit, Some(stable.clone()));
+    assert_eq!(git.rev_parse("HEAD").unwrap(), stable);
+
+    let decks = Updater::new(path.to_str().unwrap().to_string(), settings)
+        .with_pin(Pin::Rev(first.clone()))
+        .generate(&mut state)
+        .unwrap();
+    assert_eq!(decks["deck"].updated[0].card.back, "<p>First</p>");
+    assert_eq!(state.last_commit, Some(first.clone()));
+    assert_eq!(
+        std::fs::read_to_string(path.join("deck/cards.md")).unwrap(),
+        "## A\nFirst\n"
+    );
+    assert!(git.update(&Pin::Tag("missing".to_string())).is_err());
+}
+
+/// libgit2 only clones shallow over the network, the clone is made by git
+#[rstest]
+pub fn test_shallow_clone_fetches_the_old_commit() {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    upstream.write("deck/cards.md", "## A\nSecond\n");
+    upstream.commit("second");
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("clone");
+    let url = format!("file://{}", upstream.path());
+    let git = Git::clone(
+        &url,
+        path.to_str().unwrap(),
+        GitBackend::System,
+        &Auth::default(),
+        &Pin::Default,
+    )
+    .unwrap();
+    assert!(git.rev_parse(&first).is_err());
+    upstream.write("deck/cards.md", "## A\nThird\n");
+    let third = upstream.commit("third");
+
+    let mut state = SyncState {
+        last_commit: Some(first.clone()),
+    };
+    let settings = Settings {
+        git: GitBackend::System,
+        ..Default::default()
+    };
+    let decks = Updater::new(path.to_str().unwrap().to_string(), settings)
+        .generate(&mut state)
+        .unwrap();
+    assert_eq!(decks["deck"].updated[0].card.back, "<p>Third</p>");
+    assert_eq!(state.last_commit, Some(third.clone()));
+    assert_eq!(git.rev_parse("HEAD").unwrap(), third);
+    assert_eq!(
+        std::fs::read_to_string(path.join(".git/shallow"))
+            .unwrap()
+            .lines()
+            .count(),
+        2
+    );
+}
+
+#[rstest]
+pub fn test_unreachable_commit_needs_reconcile(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    upstream.commit("init");
+    let clone = upstream.clone_repo();
+    upstream.write("deck/cards.md", "## A\nSecond\n");
+    upstream.commit("second");
+
+    let gone = "0".repeat(40);
+    let mut state = SyncState {
+        last_commit: Some(gone.clone()),
+    };
+    let settings = Settings {
+        git: backend,
+        ..Default::default()
+    };
+    let error = Updater::new(clone.path(), settings)
+        .generate(&mut state)
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<GitError>(),
+        Some(GitError::Unreachable(commit)) if *commit == gone
+    ));
+    assert_eq!(state.last_commit, Some(gone));
+}
+
+#[rstest]
+pub fn test_unreadable_file_keeps_its_cards(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let repo = TestRepo::new();
+    repo.write("deck/a.md", "## A\nFirst\n");
+    repo.write("deck/b.md", "## B\nFirst\n");
+    let from = repo.commit("init");
+    std::fs::write(format!("{}/deck/a.md", repo.path()), b"## A\n\xff\xfe\n").unwrap();
+    repo.write("deck/b.md", "## B\nEdited\n");
+    let to = repo.commit("latin-1");
+
+    let settings = Settings {
+        git: backend,
+        ..Default::default()
+    };
+    let diff = Git::open(repo.path(), backend).diff(&from, &to).unwrap();
+    let decks = Updater::new(repo.path(), settings)
+        .generate_decks_from_diff(&diff, &from, &to)
+        .unwrap();
+    let deck = decks.get("deck").unwrap();
+    assert!(deck.deleted.is_empty());
+    assert!(deck.added.is_empty());
+    assert_eq!(deck.updated.len(), 1);
+    assert_eq!(deck.updated[0].card.back, "<p>Edited</p>");
+    assert_eq!(deck.diagnostics.len(), 1);
+    assert_eq!(deck.diagnostics[0].file, "deck/a.md");
+}
+
+#[rstest]
+pub fn test_fetch_errors_are_not_unreachable(
+    #[values(GitBackend::Builtin, GitBackend::System)] backend: GitBackend,
+) {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    upstream.commit("init");
+    let clone = upstream.clone_repo();
+    let dir = tempfile::TempDir::new().unwrap();
+    let missing = format!("file://{}", dir.path().join("missing").display());
+    clone.git(&["remote", "set-url", "origin", &missing]);
+
+    let gone = "0".repeat(40);
+    let error = Git::open(clone.path(), backend)
+        .ensure_commit(&gone)
+        .unwrap_err();
+    assert!(!matches!(error, GitError::Unreachable(_)));
+}
+
+/// libgit2 only clones shallow over the network, the clone is made by git
+#[rstest]
+pub fn test_shallow_clone_deepens_to_an_old_rev() {
+    let upstream = TestRepo::new();
+    upstream.write("deck/cards.md", "## A\nFirst\n");
+    let first = upstream.commit("init");
+    upstream.write("deck/cards.md", "## A\nSecond\n");
+    upstream.commit("second");
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("clone");
+    let url = format!("file://{}", upstream.path());
+    let git = Git::clone(
+        &url,
+        path.to_str().unwrap(),
+        GitBackend::System,
+        &Auth::default(),
+        &Pin::Default,
+    )
+    .unwrap();
+    assert!(git.rev_parse(&first).is_err());
+    // Version 0 of the protocol refuses unadvertised ids, as most servers do
+    let status = std::process::Command::new("git")
+        .current_dir(&path)
+        .args(["config", "protocol.version", "0"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let update = git.update(&Pin::Rev(first.clone())).unwrap();
+    assert_eq!(update.to_commit, first);
+    assert_eq!(
+        std::fs::read_to_string(path.join("deck/cards.md")).unwrap(),
+        "## A\nFirst\n"
+    );
+}
@@ -1,85 +1,133 @@
 use std::{
     collections::{HashMap, HashSet},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
-use gitpatch::Patch;
-
 use crate::{
-    data::{Card, DeckOutput, Output},
+    config::Settings,
+    data::{Card, DeckEvent, DeckOutput, Diagnostic, Media, Output, UpdatedCard},
     generator::Generator,
-    git::{Git, GitUpdate},
+    git::{Change, Git, GitUpdate, Pin},
+    source::{GitTree, Source},
+    state::SyncState,
 };
 
+/// Updated decks as they are at the commit being synced to
+#[derive(Default)]
+pub struct DecksAtCommit {
+    pub cards: HashMap<String, Vec<Card>>,
+    pub media: Media,
+    pub diagnostics: HashMap<String, Vec<Diagnostic>>,
+}
+
 #[derive(Debug)]
 pub struct Updater {
     git: Git,
     repo_path: PathBuf,
+    /// Folder holding the decks, relative to the repository
+    target: PathBuf,
+    settings: Settings,
+    pin: Pin,
 }
 
 impl Updater {
-    pub fn new(repo: String) -> Self {
+    pub fn new(repo: String, settings: Settings) -> Self {
         let repo_path = PathBuf::from_str(repo.as_str()).unwrap();
-        let git = Git::new(repo);
-        Self { git, repo_path }
-    }
-
-    fn root_folder_of_patch(path: &str) -> String {
-        let mut path = PathBuf::from_str(path).unwrap();
-        if path.starts_with("a/") {
-            path = path.strip_prefix("a/").unwrap().to_path_buf();
-        }
-
-        if path.starts_with("b/") {
-            path = path.strip_prefix("b/").unwrap().to_path_buf();
+        let git = Git::open_with_auth(repo, settings.git, settings.auth.clone());
+        Self {
+            git,
+            repo_path,
+            target: PathBuf::new(),
+            settings,
+            pin: Pin::Default,
         }
+    }
 
-        path.parent().unwrap().to_str().unwrap().to_string()
+    /// Take the decks from `target_path`, in the repository, and name them from it as `Init` does
+    pub fn with_target(mut self, target_path: &Path) -> Self {
+        self.target = target_path
+            .strip_prefix(&self.repo_path)
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        self
     }
 
-    pub fn get_folder_of_patch(patch: &Patch) -> Vec<String> {
-        let mut paths = Vec::new();
-        if patch.old.path != "/dev/null" {
-            paths.push(Self::root_folder_of_patch(&patch.old.path));
-        }
+    /// Follow `pin` instead of the branch of the checkout
+    pub fn with_pin(mut self, pin: Pin) -> Self {
+        self.pin = pin;
+        self
+    }
 
-        if patch.new.path != "/dev/null" {
-            paths.push(Self::root_folder_of_patch(&patch.new.path));
-        }
+    /// Folders holding the files of `changes`, the root folder is the empty string
+    pub fn get_folder_with_diff(changes: &[Change]) -> HashSet<String> {
+        changes
+            .iter()
+            .flat_map(|change| [&change.old_path, &change.new_path])
+            .flatten()
+            .filter_map(|path| Some(path.parent()?.to_str()?.to_string()))
+            .collect()
+    }
 
-        paths
+    /// Path of the deck of `folder`, relative to the target, when it holds one
+    fn deck_path(&self, folder: &str) -> Option<String> {
+        let deck = Path::new(folder)
+            .strip_prefix(&self.target)
+            .ok()?
+            .to_str()?;
+        (!deck.is_empty() && !deck.split('/').any(|part| part.starts_with('.')))
+            .then(|| deck.to_string())
     }
 
-    pub fn get_folder_with_diff(diff: &str) -> anyhow::Result<HashSet<String>> {
-        let Ok(patchs) = gitpatch::Patch::from_multiple(diff) else {
-            return Err(anyhow::anyhow!("Output diff is not correct"));
-        };
+    fn deck_folder(&self, deck_path: &str) -> PathBuf {
+        self.repo_path.join(&self.target).join(deck_path)
+    }
 
-        let decks = patchs
+    /// Whether `changes` add, edit or remove the macros file
+    fn diff_touches_macros(&self, changes: &[Change]) -> bool {
+        let macros_file = self.settings.macros_file();
+        changes
             .iter()
-            .flat_map(Self::get_folder_of_patch)
-            .collect::<HashSet<String>>();
+            .flat_map(|change| [&change.old_path, &change.new_path])
+            .any(|path| path.as_ref() == Some(&macros_file))
+    }
 
-        Ok(decks)
+    /// Whether `changes` add, edit or remove a file other than markdown, which cards of any
+    /// folder may embed
+    fn diff_touches_media(changes: &[Change]) -> bool {
+        changes
+            .iter()
+            .flat_map(|change| [&change.old_path, &change.new_path])
+            .flatten()
+            .filter(|path| {
+                !path
+                    .components()
+                    .any(|part| part.as_os_str().to_string_lossy().starts_with('.'))
+            })
+            .any(|path| path.extension().is_none_or(|extension| extension != "md"))
     }
 
     pub fn get_card_of_from_commit(
         &self,
         updated_folder: &HashSet<String>,
         from_commit: &str,
-    ) -> anyhow::Result<HashMap<String, HashSet<String>>> {
-        self.git.checkout(from_commit)?;
+    ) -> anyhow::Result<HashMap<String, HashMap<String, String>>> {
+        let source = GitTree::new(&self.git, from_commit);
 
-        let mut old_cards: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut old_cards: HashMap<String, HashMap<String, String>> = HashMap::new();
         for i in updated_folder {
-            let hashes: HashSet<String> = Generator {
-                subproject_path: self.repo_path.join(i).as_path(),
+            let subproject_path = self.deck_folder(i);
+            if !source.is_dir(&subproject_path) {
+                continue;
             }
-            .generate_card_from_folder()
-            .iter()
-            .map(|f| f.hash.clone())
-            .collect();
+
+            let hashes: HashMap<String, String> =
+                Generator::new(&source, &self.settings, subproject_path.as_path())
+                    .with_root(&self.repo_path)
+                    .generate_card_from_folder(&Self::deck_name(i))
+                    .into_iter()
+                    .map(|f| (f.id, f.hash))
+                    .collect();
 
             old_cards.insert(i.clone(), hashes);
         }
@@ -87,78 +135,231 @@ impl Updater {
         Ok(old_cards)
     }
 
+    /// Cards of `to_commit`, the files it cannot read keep their cards of `from_commit`
     pub fn get_cards_of_to_commit(
         &self,
         updated_folder: &HashSet<String>,
+        from_commit: &str,
         to_commit: &str,
-    ) -> anyhow::Result<HashMap<String, Vec<Card>>> {
-        self.git.checkout(to_commit)?;
+    ) -> anyhow::Result<DecksAtCommit> {
+        let source = GitTree::new(&self.git, to_commit);
+        let old_source = GitTree::new(&self.git, from_commit);
 
-        let mut decks_cards = HashMap::new();
+        let mut decks = DecksAtCommit::default();
         for i in updated_folder {
-            let cards = Generator {
-                subproject_path: self.repo_path.join(i).as_path(),
+            let subproject_path = self.deck_folder(i);
+            if !source.is_dir(&subproject_path) {
+                continue;
+            }
+
+            let generator = Generator::new(&source, &self.settings, subproject_path.as_path())
+                .with_root(&self.repo_path);
+            let mut cards = generator.generate_card_from_folder(&Self::deck_name(i));
+            let old_generator =
+                Generator::new(&old_source, &self.settings, subproject_path.as_path())
+                    .with_root(&self.repo_path);
+            for file in generator.take_unreadable() {
+                if let Ok(content) = old_source.read_to_string(&file) {
+                    cards.extend(old_generator.generate_card_from_input(
+                        &content,
+                        &file,
+                        Some(&Self::deck_name(i)),
+                    ));
+                }
+            }
+            decks.media.extend(generator.take_media());
+            decks
+                .diagnostics
+                .insert(i.clone(), generator.take_diagnostics());
+
+            decks.cards.insert(i.clone(), cards);
+        }
+
+        Ok(decks)
+    }
+
+    /// Match each created deck with the deleted deck sharing most of its cards
+    fn find_renames(
+        cards_from_commit: &HashMap<String, HashMap<String, String>>,
+        cards_to_commit: &HashMap<String, Vec<Card>>,
+    ) -> HashMap<String, String> {
+        let mut deleted: HashSet<&String> = cards_from_commit
+            .keys()
+            .filter(|f| !cards_to_commit.contains_key(*f))
+            .collect();
+
+        let mut renames = HashMap::new();
+        for (deck_path, cards) in cards_to_commit {
+            if cards_from_commit.contains_key(deck_path) {
+                continue;
+            }
+
+            let best = deleted
+                .iter()
+                .map(|old_path| {
+                    let old_deck = &cards_from_commit[*old_path];
+                    let shared = cards
+                        .iter()
+                        .filter(|f| old_deck.contains_key(&f.id))
+                        .count();
+                    (shared, old_deck.len(), *old_path)
+                })
+                .filter(|(shared, old_len, _)| *shared > 0 && shared * 2 >= *old_len)
+                .max_by_key(|(shared, _, old_path)| (*shared, std::cmp::Reverse(*old_path)));
+
+            if let Some((_, _, old_path)) = best {
+                deleted.remove(old_path);
+                renames.insert(deck_path.clone(), old_path.clone());
+            }
+        }
+
+        renames
+    }
+
+    fn diff_deck(old_deck: &HashMap<String, String>, cards: &[Card]) -> DeckOutput {
+        let new_cards_id: HashSet<&String> = cards.iter().map(|f| &f.id).collect();
+        let deleted = old_deck
+            .iter()
+            .filter(|(id, _)| !new_cards_id.contains(id))
+            .map(|(_, hash)| hash.clone())
+            .collect::<Vec<_>>();
+
+        let mut added = Vec::new();
+        let mut updated = Vec::new();
+        for card in cards {
+            match old_deck.get(&card.id) {
+                None => added.push(card.clone()),
+                Some(old_hash) if *old_hash != card.hash => updated.push(UpdatedCard {
+                    old_hash: old_hash.clone(),
+                    card: card.clone(),
+                }),
+                Some(_) => {}
             }
-            .generate_card_from_folder();
+        }
 
-            decks_cards.insert(i.clone(), cards);
+        DeckOutput {
+            added,
+            updated,
+            deleted,
+            ..Default::default()
         }
+    }
 
-        Ok(decks_cards)
+    fn deck_name(deck_path: &str) -> String {
+        deck_path.replace('/', "::")
     }
 
     pub fn generate_decks_from_diff(
         &self,
-        diff: &str,
+        diff: &[Change],
         from_commit: &str,
         to_commit: &str,
     ) -> anyhow::Result<Output> {
-        let updated_folder = Self::get_folder_with_diff(diff)?;
+        let mut updated_folder = Self::get_folder_with_diff(diff);
+        // Any card may use a macro or a media file, so every deck is generated again
+        if self.diff_touches_macros(diff) || Self::diff_touches_media(diff) {
+            updated_folder.extend(self.git.ls_dirs(from_commit)?);
+            updated_folder.extend(self.git.ls_dirs(to_commit)?);
+        }
+        let updated_folder = updated_folder
+            .iter()
+            .filter_map(|f| self.deck_path(f))
+            .collect();
         let cards_from_commit = self.get_card_of_from_commit(&updated_folder, from_commit)?;
-        let cards_to_commit = self.get_cards_of_to_commit(&updated_folder, to_commit)?;
+        let DecksAtCommit {
+            cards: cards_to_commit,
+            media,
+            mut diagnostics,
+        } = self.get_cards_of_to_commit(&updated_folder, from_commit, to_commit)?;
+        let renames = Self::find_renames(&cards_from_commit, &cards_to_commit);
 
         let mut output = Output::default();
+        let empty = HashMap::new();
 
         for (deck_path, cards) in &cards_to_commit {
-            let new_cards_hash: HashSet<String> = cards.iter().map(|f| f.hash.clone()).collect();
-            let Some(old_deck) = cards_from_commit.get(deck_path) else {
-                continue;
+            let renamed_from = renames.get(deck_path);
+            let old_deck = cards_from_commit.get(renamed_from.unwrap_or(deck_path));
+
+            let mut deck = Self::diff_deck(old_deck.unwrap_or(&empty), cards);
+            deck.event = match (old_deck, renamed_from) {
+                (_, Some(_)) => Some(DeckEvent::Renamed),
+                (None, None) => Some(DeckEvent::Created),
+                (Some(old_deck), None) if cards.is_empty() && !old_deck.is_empty() => {
+                    Some(DeckEvent::Emptied)
+                }
+                (Some(_), None) => None,
             };
+            deck.renamed_from = renamed_from.map(|f| Self::deck_name(f));
+            deck.collect_media(&media);
+            deck.diagnostics = diagnostics.remove(deck_path).unwrap_or_default();
 
-            let deleted = old_deck
-                .difference(&new_cards_hash)
-                .cloned()
-                .collect::<Vec<_>>();
-
-            let added_cards = new_cards_hash.difference(old_deck).collect::<Vec<_>>();
+            output.insert(Self::deck_name(deck_path), deck);
+        }
 
-            let added: Vec<_> = cards
-                .iter()
-                .filter(|f| added_cards.contains(&&f.hash))
-                .cloned()
-                .collect();
+        let renamed: HashSet<&String> = renames.values().collect();
+        for (deck_path, old_deck) in &cards_from_commit {
+            if cards_to_commit.contains_key(deck_path) || renamed.contains(deck_path) {
+                continue;
+            }
 
             output.insert(
-                deck_path.replace('/', "::").clone(),
-                DeckOutput { added, deleted },
+                Self::deck_name(deck_path),
+                DeckOutput {
+                    deleted: old_deck.values().cloned().collect(),
+                    event: Some(DeckEvent::Deleted),
+                    ..Default::default()
+                },
             );
         }
 
         Ok(output)
     }
 
-    pub fn generate(&self) -> anyhow::Result<Output> {
+    /// Tell, on the deck of each file, that its local edits are not synced
+    fn report_local_changes(&self, output: &mut Output, local_changes: &[PathBuf]) {
+        for path in local_changes {
+            let Some(deck_path) = path
+                .parent()
+                .and_then(|f| f.to_str())
+                .and_then(|f| self.deck_path(f))
+            else {
+                continue;
+            };
+
+            output
+                .entry(Self::deck_name(&deck_path))
+                .or_default()
+                .diagnostics
+                .push(Diagnostic {
+                    file: path.display().to_string(),
+                    line: 1,
+                    message: "Edited in the local clone, the cards come from the upstream instead"
+                        .to_string(),
+                });
+        }
+    }
+
+    /// Cards changed since the commit recorded in `state`, which is moved to the fetched commit
+    ///
+    /// Fails with [`crate::git::GitError::Unreachable`] when the recorded commit cannot be fetched anymore.
+    pub fn generate(&self, state: &mut SyncState) -> anyhow::Result<Output> {
         let GitUpdate {
             from_commit,
             to_commit,
-        } = self.git.update()?;
-
-        let Some(diff) = self.git.diff(&from_commit, &to_commit) else {
-            return Err(anyhow::anyhow!(
-                "Cannot get a diff between {from_commit} and {to_commit}"
-            ));
+            local_changes,
+        } = self.git.update(&self.pin)?;
+        let from_commit = state.last_commit.clone().unwrap_or(from_commit);
+
+        let mut output = if from_commit == to_commit {
+            Output::default()
+        } else {
+            self.git.ensure_commit(&from_commit)?;
+            let diff = self.git.diff(&from_commit, &to_commit)?;
+            self.generate_decks_from_diff(&diff, &from_commit, &to_commit)?
         };
+        self.report_local_changes(&mut output, &local_changes);
+        state.last_commit = Some(to_commit);
 
-        self.generate_decks_from_diff(&diff, &from_commit, &to_commit)
+        Ok(output)
     }
 }
@@ -3,41 +3,78 @@ import os
 from pathlib import Path
 from anki.collection import Collection
 from anki.decks import DeckId
+from anki.models import MODEL_CLOZE
 from aqt import mw
 from aqt import gui_hooks
+from aqt.qt import QAction
 from aqt.operations import QueryOp
-from .gencore import from_config
+from aqt.utils import showWarning
+from .gencore import (
+    KATEX_SCRIPTS,
+    KATEX_STYLESHEET,
+    AuthError,
+    UnreachableCommit,
+    commit_state,
+    from_config,
+    katex_assets,
+    reconcile,
+)
 
 BASE_PATH = Path(__file__).parent / "user_files"
 
-static_html = """
-<link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/katex.min.css" integrity="sha384-nB0miv6/jRmo5UMMR1wu3Gz6NLsoTkbqJghGIsx//Rlm+ZU03BU6SQNC66uf4l5+" crossorigin="anonymous">
-<script defer src="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/katex.min.js" integrity="sha384-7zkQWkzuo3B5mTepMUcHkMB5jZaolc2xDwL6VFqjFALcbeS9Ggm/Yr2r3Dy4lfFg" crossorigin="anonymous"></script>
-<script defer src="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/contrib/auto-render.min.js" integrity="sha384-43gviWU0YVjaDtb/GhzOouOXtZMP/7XUzwPTstBeZFe/+rCMvRwr4yROQP43s0Xk" crossorigin="anonymous" onload="renderMathInElement(document.body);"></script>
-<script>
-    renderMathInElement(document.body, {
-      // customised options
-      // • auto-render specific keys, e.g.:
-      delimiters: [
-          {left: '$$', right: '$$', display: true},
-          {left: '$', right: '$', display: false},
-      ],
-      // • rendering keys, e.g.:
-      throwOnError : false
-    });
-</script>
-"""
+# Also loaded by the note types of the packages of `write_apkg`
+katex_stylesheet = KATEX_STYLESHEET
+
+static_html = katex_stylesheet + KATEX_SCRIPTS
+
+
+def math_html() -> str:
+    # Anki renders MathJax and MathML by itself, the HTML of KaTeX only needs its stylesheet
+    assets = katex_assets("./config.toml")
+    return {"scripts": static_html, "stylesheet": katex_stylesheet}.get(assets, "")
+
+
+def update_math(col: Collection, model, html: str) -> None:
+    """Replace the KaTeX files loaded by the templates of `model` with `html`"""
+    changed = False
+    for template in model["tmpls"]:
+        qfmt = template["qfmt"]
+        for previous in (static_html, katex_stylesheet):
+            if qfmt.endswith(previous):
+                qfmt = qfmt[: -len(previous)]
+                break
+
+        if qfmt + html != template["qfmt"]:
+            template["qfmt"] = qfmt + html
+            changed = True
+
+    if changed:
+        col.models.save(model)
 
 
 @dataclass
 class Card:
+    id: str
+    guid: str
+    kind: str
     front: str
     back: str
     hash: str
+    tags: list[str]
+    media: list[str]
 
     @staticmethod
-    def from_dict(dict_data: dict[str, str]) -> "Card":
-        return Card(dict_data["front"], dict_data["back"], dict_data["hash"])
+    def from_dict(dict_data: dict) -> "Card":
+        return Card(
+            dict_data["id"],
+            dict_data["guid"],
+            dict_data["kind"],
+            dict_data["front"],
+            dict_data["back"],
+            dict_data["hash"],
+            dict_data["tags"],
+            dict_data["media"],
+        )
 
     @staticmethod
     def from_list(list: list[dict[str, str]]) -> list["Card"]:
@@ -48,14 +85,51 @@ class Card:
         return len(col.find_cards(query)) != 0
 
 
+@dataclass
+class UpdatedCard:
+    old_hash: str
+    card: Card
+
+    @staticmethod
+    def from_dict(dict_data: dict) -> "UpdatedCard":
+        return UpdatedCard(dict_data["old_hash"], Card.from_dict(dict_data["card"]))
+
+
+@dataclass
+class Diagnostic:
+    file: str
+    line: int
+    message: str
+
+    @staticmethod
+    def from_dict(dict_data: dict) -> "Diagnostic":
+        return Diagnostic(dict_data["file"], dict_data["line"], dict_data["message"])
+
+    def __str__(self) -> str:
+        return f"{self.file}:{self.line}: {self.message}"
+
+
 @dataclass
 class DeckOutput:
     added: list[Card]
+    updated: list[UpdatedCard]
     deleted: list[str]
+    event: str | None
+    renamed_from: str | None
+    media: dict[str, bytes]
+    diagnostics: list[Diagnostic]
 
     @staticmethod
-    def from_dict(dict_data: dict[str, list[dict[str, str] | str]]):
-        return DeckOutput(Card.from_list(dict_data["added"]), dict_data["deleted"])
+    def from_dict(dict_data: dict):
+        return DeckOutput(
+            Card.from_list(dict_data["added"]),
+            [UpdatedCard.from_dict(d) for d in dict_data["updated"]],
+            dict_data["deleted"],
+            dict_data["event"],
+            dict_data["renamed_from"],
+            dict_data["media"],
+            [Diagnostic.from_dict(d) for d in dict_data["diagnostics"]],
+        )
 
 
 @dataclass
@@ -67,12 +141,12 @@ class Output:
         return Output({k: DeckOutput.from_dict(v) for (k, v) in output.items()})
 
 
-def create_model():
+def create_model(name: str = "Ankill", reverse: str | None = None, optional: bool = False):
     col = mw.col
     if col is None:
         raise Exception("Error")
 
-    model = col.models.new("Ankill")
+    model = col.models.new(name)
     recto = col.models.new_field("Recto")
     col.models.add_field(model, recto)
     verso = col.models.new_field("Verso")
@@ -80,35 +154,113 @@ def create_model():
     hash = col.models.new_field("Hash")
     hash["collapsed"] = True
     col.models.add_field(model, hash)
+    if optional:
+        col.models.add_field(model, col.models.new_field("Inverser"))
     template = col.models.new_template("Carte")
-    template["qfmt"] = "{{Recto}}" + static_html
+    template["qfmt"] = "{{Recto}}" + math_html()
     template["afmt"] = "{{FrontSide}}\n\n<hr id=answer>\n\n{{Verso}}"
     col.models.add_template(model, template)
+    if reverse is not None:
+        inverse = col.models.new_template("Carte inverse")
+        inverse["qfmt"] = reverse + math_html()
+        inverse["afmt"] = "{{FrontSide}}\n\n<hr id=answer>\n\n{{Recto}}"
+        col.models.add_template(model, inverse)
     return model
 
 
-def add_cards(col: Collection, deck_id: DeckId, cards: list[Card]):
-    model = col.models.by_name("Ankill")
-    if model is None:
-        return
+def create_reversed_model():
+    return create_model("Ankill (reversed)", "{{Verso}}")
+
+
+def create_optional_reversed_model():
+    return create_model(
+        "Ankill (optional reversed)", "{{#Inverser}}{{Verso}}{{/Inverser}}", optional=True
+    )
+
+
+def create_cloze_model():
+    col = mw.col
+    if col is None:
+        raise Exception("Error")
+
+    model = col.models.new("Ankill Cloze")
+    model["type"] = MODEL_CLOZE
+    text = col.models.new_field("Texte")
+    col.models.add_field(model, text)
+    extra = col.models.new_field("Extra")
+    col.models.add_field(model, extra)
+    hash = col.models.new_field("Hash")
+    hash["collapsed"] = True
+    col.models.add_field(model, hash)
+    template = col.models.new_template("Texte à trous")
+    template["qfmt"] = "{{cloze:Texte}}" + math_html()
+    template["afmt"] = "{{cloze:Texte}}<br>\n{{Extra}}"
+    col.models.add_template(model, template)
+    return model
+
+
+MODELS = {
+    "basic": ("Ankill", create_model),
+    "basic-and-reversed": ("Ankill (reversed)", create_reversed_model),
+    "optional-reversed": ("Ankill (optional reversed)", create_optional_reversed_model),
+    "cloze": ("Ankill Cloze", create_cloze_model),
+}
+
+
+def fill_note(note, card: Card):
+    note.fields[0] = card.front
+    note.fields[1] = card.back
+    note.fields[2] = card.hash
+    note.tags = list(card.tags)
 
+
+def add_cards(col: Collection, deck_id: DeckId, cards: list[Card]):
     for card in cards:
         if card.exists_in(deck_id, col):
             continue
 
+        model = col.models.by_name(MODELS[card.kind][0])
+        if model is None:
+            continue
+
         note = col.new_note(model)
-        note.fields[0] = card.front
-        note.fields[1] = card.back
-        note.fields[2] = card.hash
+        # The guid lets a reconcile recognise the note once its content changed
+        note.guid = card.guid
+        fill_note(note, card)
+        if card.kind == "optional-reversed":
+            # Only the default of a new note, the user may turn it off
+            note.fields[3] = "y"
         _ = col.add_note(note, deck_id)
 
 
+def update_cards(col: Collection, did: DeckId, updated: list[UpdatedCard]):
+    for update in updated:
+        query = f"did:{did} hash:{update.old_hash}"
+        for note_id in col.find_notes(query):
+            note = col.get_note(note_id)
+            if note.note_type()["name"] != MODELS[update.card.kind][0]:
+                # The note type of a note cannot change in place
+                col.remove_notes([note_id])
+                add_cards(col, did, [update.card])
+                continue
+
+            fill_note(note, update.card)
+            col.update_note(note)
+
+
 def delete_cards(col: Collection, did: DeckId, hashes: list[str]):
     for hash in hashes:
         query = f"did:{did} hash:{hash}"
         col.remove_notes_by_card(list(col.find_cards(query)))
 
 
+def write_media(col: Collection, media: dict[str, bytes]):
+    # Names are content hashes, an existing file is already the right one
+    for name, data in media.items():
+        if not col.media.have(name):
+            col.media.write_data(name, data)
+
+
 def create_or_get_deck_for_name(col: Collection, deck_name: str) -> DeckId:
     deckid = col.decks.id_for_name(deck_name)
     if deckid is None:
@@ -120,32 +272,105 @@ def create_or_get_deck_for_name(col: Collection, deck_name: str) -> DeckId:
     return deckid
 
 
+def rename_deck(col: Collection, old_name: str, new_name: str):
+    deckid = col.decks.id_for_name(old_name)
+    if deckid is None:
+        return
+
+    existing = col.decks.id_for_name(new_name)
+    if existing is None:
+        col.decks.rename(deckid, new_name)
+        return
+
+    # A deck already has the new name, the cards join it with their history
+    col.set_deck(list(col.find_cards(f"did:{deckid}")), existing)
+    remove_deck_if_empty(col, deckid)
+
+
+def remove_deck_if_empty(col: Collection, did: DeckId):
+    if col.decks.card_count(did, include_subdecks=True) == 0 and not col.decks.children(did):
+        col.decks.remove([did])
+
+
 def update_from_config() -> Output:
     config_path = "./config.toml"
     value = from_config(config_path)
     return Output.from_dict(value)
 
 
+def collect_inventory(col: Collection) -> dict[str, dict[str, str]]:
+    inventory: dict[str, dict[str, str]] = {}
+    for deck in col.decks.all_names_and_ids():
+        hashes: dict[str, str] = {}
+        # By id, a name may hold quotes or the wildcards of the search syntax, did: skips the subdecks
+        query = f"note:Ankill* did:{deck.id}"
+        for note_id in col.find_notes(query):
+            note = col.get_note(note_id)
+            hashes[note.guid] = note["Hash"]
+        if hashes:
+            inventory[deck.name] = hashes
+
+    return inventory
+
+
+def reconcile_from_config(col: Collection) -> Output:
+    config_path = "./config.toml"
+    value = reconcile(config_path, collect_inventory(col))
+    return Output.from_dict(value)
+
+
 class Config:
     def __init__(self, url: str, col: Collection) -> None:
         self.url: str = url
         self.collection: Collection = col
 
-    def execute(self) -> int:
-        decks = update_from_config()
-        if "Ankill" not in [n.name for n in self.collection.models.all_names_and_ids()]:
-            self.collection.models.save(create_model())
+    def fetch(self, full: bool) -> Output:
+        try:
+            return reconcile_from_config(self.collection) if full else update_from_config()
+        except UnreachableCommit:
+            # The commit the decks come from is gone, compare them with the repositories instead
+            return reconcile_from_config(self.collection)
+
+    def execute(self, full: bool = False) -> list[Diagnostic]:
+        try:
+            decks = self.fetch(full)
+        except AuthError as error:
+            # Nothing was synced, the collection stays as it is
+            return [Diagnostic(self.url, 1, f"Cannot authenticate, check the auth table of the repository: {error}")]
+        model_names = [n.name for n in self.collection.models.all_names_and_ids()]
+        html = math_html()
+        for name, create in MODELS.values():
+            if name not in model_names:
+                self.collection.models.save(create())
+            else:
+                # The math settings may have changed since the note type was created
+                update_math(self.collection, self.collection.models.by_name(name), html)
 
         for name, diff in decks.decks.items():
+            if diff.event == "renamed" and diff.renamed_from is not None:
+                rename_deck(self.collection, diff.renamed_from, name)
+
             deckid = create_or_get_deck_for_name(self.collection, name)
+            write_media(self.collection, diff.media)
             delete_cards(self.collection, deckid, diff.deleted)
+            update_cards(self.collection, deckid, diff.updated)
             add_cards(
                 self.collection,
                 deckid,
                 diff.added,
             )
 
-        return 0
+            if diff.event == "deleted":
+                remove_deck_if_empty(self.collection, deckid)
+
+        # The collection holds the output, the next sync can start from these commits
+        commit_state(self.url)
+        return [d for diff in decks.decks.values() for d in diff.diagnostics]
+
+
+def show_diagnostics(diagnostics: list[Diagnostic]) -> None:
+    if diagnostics:
+        showWarning("\n".join(str(d) for d in diagnostics), title="genanki-rs")
 
 
 def init() -> None:
@@ -154,10 +379,28 @@ def init() -> None:
     op = QueryOp(
         parent=mw,
         op=lambda col: Config("./config.toml", col).execute(),
-        success=lambda e: None,
+        success=show_diagnostics,
     )
     op.with_progress(label="Updating your decks...").run_in_background()
     mw.deckBrowser.refresh()
 
 
+def init_reconcile() -> None:
+    os.chdir(BASE_PATH)
+    mw.create_backup_now()
+    op = QueryOp(
+        parent=mw,
+        op=lambda col: Config("./config.toml", col).execute(full=True),
+        success=lambda diagnostics: (mw.deckBrowser.refresh(), show_diagnostics(diagnostics)),
+    )
+    op.with_progress(label="Reconciling your decks...").run_in_background()
+
+
+def add_menu() -> None:
+    action = QAction("Reconcile genanki-rs decks", mw)
+    action.triggered.connect(init_reconcile)
+    mw.form.menuTools.addAction(action)
+
+
 gui_hooks.profile_did_open.append(init)
+gui_hooks.main_window_did_init.append(add_menu)
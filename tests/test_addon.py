@@ -0,0 +1,244 @@
+import importlib.util
+import json
+import os
+import sqlite3
+import subprocess
+import sys
+import tempfile
+import types
+import unittest
+import zipfile
+from pathlib import Path
+
+SRC = Path(__file__).resolve().parent.parent / "src"
+
+
+def load_addon():
+    """The add-on, with Anki replaced by empty modules"""
+    stubs = {
+        "anki": {},
+        "anki.collection": {"Collection": object},
+        "anki.decks": {"DeckId": int},
+        "anki.models": {"MODEL_CLOZE": 1},
+        "aqt": {"mw": None, "gui_hooks": types.SimpleNamespace(profile_did_open=[], main_window_did_init=[])},
+        "aqt.qt": {"QAction": object},
+        "aqt.operations": {"QueryOp": object},
+        "aqt.utils": {"showWarning": print},
+    }
+    for name, attributes in stubs.items():
+        module = sys.modules.setdefault(name, types.ModuleType(name))
+        for key, value in attributes.items():
+            setattr(module, key, value)
+
+    spec = importlib.util.spec_from_file_location("addon", SRC / "__init__.py", submodule_search_locations=[str(SRC)])
+    addon = importlib.util.module_from_spec(spec)
+    sys.modules["addon"] = addon
+    spec.loader.exec_module(addon)
+    return addon
+
+
+def git(cwd: Path, *args: str) -> None:
+    subprocess.run(
+        ["git", "-c", "user.name=test", "-c", "user.email=test@example.org", *args],
+        cwd=cwd,
+        check=True,
+        capture_output=True,
+    )
+
+
+@unittest.skipUnless((SRC / "gencore.so").exists(), "gencore.so is built by `make build` or `make test`")
+class TestOutput(unittest.TestCase):
+    def test_from_config_round_trip(self):
+        addon = load_addon()
+        with tempfile.TemporaryDirectory() as directory:
+            directory = Path(directory)
+            upstream = directory / "upstream"
+            (upstream / "deck").mkdir(parents=True)
+            (upstream / "deck" / "cards.md").write_text("## Dog {#dog}\nChien\n")
+            (upstream / "deck" / "broken.md").write_text("---\nkind: [reversed\n---\n## Cat {#cat}\nChat\n")
+            git(upstream, "init", "--quiet")
+            git(upstream, "add", "-A")
+            git(upstream, "commit", "--quiet", "-m", "init")
+            (directory / "config.toml").write_text(f'[repo]\ncourse = "file://{upstream}"\n')
+
+            cwd = os.getcwd()
+            os.chdir(directory)
+            try:
+                first = addon.Output.from_dict(addon.from_config("./config.toml"))
+                addon.commit_state("./config.toml")
+                (upstream / "deck" / "cards.md").write_text("## Dog {#dog}\nLe chien\n")
+                git(upstream, "commit", "--quiet", "-am", "edit")
+                second = addon.Output.from_dict(addon.from_config("./config.toml"))
+            finally:
+                os.chdir(cwd)
+
+        deck = first.decks["course::deck"]
+        self.assertEqual(sorted(card.id for card in deck.added), ["cat", "dog"])
+        self.assertTrue(all(isinstance(card, addon.Card) for card in deck.added))
+        self.assertEqual(len({card.guid for card in deck.added}), 2)
+        self.assertNotIn(deck.added[0].guid, ["cat", "dog"])
+        self.assertEqual(len(deck.diagnostics), 1)
+        self.assertEqual(deck.diagnostics[0].file, "deck/broken.md")
+
+        updated = second.decks["course::deck"].updated
+        self.assertEqual(len(updated), 1)
+        self.assertEqual(updated[0].card.id, "dog")
+        self.assertEqual(updated[0].old_hash, next(card.hash for card in deck.added if card.id == "dog"))
+
+
+class FakeNote:
+    def __init__(self, guid: str, fields: dict[str, str]):
+        self.guid = guid
+        self.fields = fields
+
+    def __getitem__(self, name: str) -> str:
+        return self.fields[name]
+
+
+class FakeCollection:
+    """Decks and cards of a collection, as far as the add-on searches and moves them"""
+
+    def __init__(self, decks: dict[str, int], cards: dict[int, int]):
+        self.names = dict(decks)
+        self.cards = dict(cards)
+        self.decks = self
+        self.notes = {card: FakeNote(f"guid-{card}", {"Hash": f"hash-{card}"}) for card in cards}
+
+    def id_for_name(self, name: str):
+        return self.names.get(name)
+
+    def rename(self, did: int, name: str) -> None:
+        old = next(old for old, id in self.names.items() if id == did)
+        self.names[name] = self.names.pop(old)
+
+    def remove(self, dids: list[int]) -> None:
+        self.names = {name: id for name, id in self.names.items() if id not in dids}
+
+    def card_count(self, did: int, include_subdecks: bool) -> int:
+        return sum(1 for deck in self.cards.values() if deck == did)
+
+    def children(self, did: int) -> list:
+        name = next(name for name, id in self.names.items() if id == did)
+        return [child for child in self.names if child.startswith(name + "::")]
+
+    def all_names_and_ids(self) -> list:
+        return [types.SimpleNamespace(name=name, id=id) for name, id in self.names.items()]
+
+    def find_cards(self, query: str) -> list[int]:
+        did = int(query.split("did:")[1].split()[0])
+        return [card for card, deck in self.cards.items() if deck == did]
+
+    find_notes = find_cards
+
+    def get_note(self, note_id: int) -> FakeNote:
+        return self.notes[note_id]
+
+    def set_deck(self, card_ids: list[int], did: int) -> None:
+        for card in card_ids:
+            self.cards[card] = did
+
+
+@unittest.skipUnless((SRC / "gencore.so").exists(), "gencore.so is built by `make build` or `make test`")
+class TestDecks(unittest.TestCase):
+    def test_rename_into_an_existing_deck(self):
+        addon = load_addon()
+        col = FakeCollection({"course::old": 1, "course::new": 2}, {10: 1, 11: 2})
+
+        addon.rename_deck(col, "course::old", "course::new")
+        self.assertEqual(col.cards, {10: 2, 11: 2})
+        self.assertEqual(col.names, {"course::new": 2})
+
+        col = FakeCollection({"course::old": 1}, {10: 1})
+        addon.rename_deck(col, "course::old", "course::new")
+        self.assertEqual(col.names, {"course::new": 1})
+
+    def test_inventory_of_decks_with_search_characters(self):
+        addon = load_addon()
+        name = 'course::"quoted" *_ deck'
+        col = FakeCollection({name: 1, name + "::child": 2}, {10: 1, 11: 2})
+
+        self.assertEqual(
+            addon.collect_inventory(col),
+            {name: {"guid-10": "hash-10"}, name + "::child": {"guid-11": "hash-11"}},
+        )
+
+
+@unittest.skipUnless((SRC / "gencore.so").exists(), "gencore.so is built by `make build` or `make test`")
+class TestApkg(unittest.TestCase):
+    def test_write_apkg_matches_the_sync(self):
+        addon = load_addon()
+        with tempfile.TemporaryDirectory() as directory:
+            directory = Path(directory)
+            upstream = directory / "upstream"
+            (upstream / "deck").mkdir(parents=True)
+            (upstream / "deck" / "cards.md").write_text("## Euler {#euler}\n$e^{i\\pi} = -1$\n")
+            git(upstream, "init", "--quiet")
+            git(upstream, "add", "-A")
+            git(upstream, "commit", "--quiet", "-m", "init")
+            (directory / "config.toml").write_text(f'[repo]\ncourse = "file://{upstream}"\n')
+
+            cwd = os.getcwd()
+            os.chdir(directory)
+            try:
+                addon.gencore.write_apkg("./config.toml", "course", "course.apkg")
+                output = addon.Output.from_dict(addon.from_config("./config.toml"))
+                with zipfile.ZipFile("course.apkg") as package:
+                    (directory / "collection.anki2").write_bytes(package.read("collection.anki2"))
+            finally:
+                os.chdir(cwd)
+
+            connection = sqlite3.connect(directory / "collection.anki2")
+            try:
+                guids = [row[0] for row in connection.execute("SELECT guid FROM notes")]
+                models = json.loads(connection.execute("SELECT models FROM col").fetchone()[0])
+            finally:
+                connection.close()
+
+        self.assertEqual(guids, [output.decks["course::deck"].added[0].guid])
+        basic = next(model for model in models.values() if model["name"] == "Ankill")
+        self.assertEqual(basic["tmpls"][0]["qfmt"], "{{Recto}}" + addon.static_html)
+
+
+@unittest.skipUnless((SRC / "gencore.so").exists(), "gencore.so is built by `make build` or `make test`")
+class TestAuth(unittest.TestCase):
+    def test_missing_token_is_reported(self):
+        addon = load_addon()
+        with tempfile.TemporaryDirectory() as directory:
+            directory = Path(directory)
+            (directory / "config.toml").write_text(
+                '[repo.course]\nurl = "https://example.org/course.git"\n'
+                '[repo.course.auth]\ntoken_env = "GENCORE_TEST_UNSET_TOKEN"\n'
+            )
+            cwd = os.getcwd()
+            os.chdir(directory)
+            try:
+                # The collection is never reached when the credentials are missing
+                diagnostics = addon.Config("./config.toml", None).execute()
+            finally:
+                os.chdir(cwd)
+
+        self.assertEqual(len(diagnostics), 1)
+        self.assertIn("Cannot authenticate", diagnostics[0].message)
+        self.assertIn("GENCORE_TEST_UNSET_TOKEN", diagnostics[0].message)
+
+
+@unittest.skipUnless((SRC / "gencore.so").exists(), "gencore.so is built by `make build` or `make test`")
+class TestMath(unittest.TestCase):
+    def test_update_math(self):
+        addon = load_addon()
+        saved = []
+        col = types.SimpleNamespace(models=types.SimpleNamespace(save=saved.append))
+        model = {"tmpls": [{"qfmt": "{{Recto}}" + addon.static_html}, {"qfmt": "{{Verso}}"}]}
+
+        addon.update_math(col, model, addon.katex_stylesheet)
+        self.assertEqual([t["qfmt"] for t in model["tmpls"]], ["{{Recto}}" + addon.katex_stylesheet, "{{Verso}}" + addon.katex_stylesheet])
+        addon.update_math(col, model, addon.katex_stylesheet)
+        self.assertEqual(len(saved), 1)
+
+        addon.update_math(col, model, "")
+        self.assertEqual([t["qfmt"] for t in model["tmpls"]], ["{{Recto}}", "{{Verso}}"])
+        self.assertEqual(len(saved), 2)
+
+
+if __name__ == "__main__":
+    unittest.main()